@@ -0,0 +1,109 @@
+use serde::Serialize;
+
+use crate::token::*;
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(tag = "type", content = "value")]
+pub enum Literal {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum Expr {
+    Assign {
+        name: Token,
+        value: Box<Expr>,
+    },
+    Binary {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        paren: Token,
+        arguments: Vec<Expr>,
+    },
+    Get {
+        object: Box<Expr>,
+        name: Token,
+    },
+    Grouping {
+        expression: Box<Expr>,
+    },
+    Literal {
+        value: Literal,
+    },
+    Logical {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Set {
+        object: Box<Expr>,
+        name: Token,
+        value: Box<Expr>,
+    },
+    Super {
+        keyword: Token,
+        method: Token,
+    },
+    This {
+        keyword: Token,
+    },
+    Unary {
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Variable {
+        name: Token,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Function {
+    pub name: Token,
+    pub params: Vec<Token>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum Stmt {
+    Block {
+        statements: Vec<Stmt>,
+    },
+    Class {
+        name: Token,
+        superclass: Option<Expr>,
+        methods: Vec<Function>,
+    },
+    Expression {
+        expression: Expr,
+    },
+    Function(Function),
+    If {
+        condition: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    Print {
+        expression: Expr,
+    },
+    Return {
+        keyword: Token,
+        value: Option<Expr>,
+    },
+    Var {
+        name: Token,
+        initializer: Option<Expr>,
+    },
+    While {
+        condition: Expr,
+        body: Box<Stmt>,
+    },
+}
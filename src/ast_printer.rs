@@ -0,0 +1,377 @@
+use serde::Serialize;
+
+use crate::ast::*;
+
+pub fn to_json<T: Serialize + ?Sized>(value: &T) -> String {
+    serde_json::to_string_pretty(value).expect("tokens and syntax trees always serialize")
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AstFormat {
+    Lisp,
+    Tree,
+    Json,
+}
+
+impl AstFormat {
+    pub fn parse(name: &str) -> Option<AstFormat> {
+        match name {
+            "lisp" => Some(AstFormat::Lisp),
+            "tree" => Some(AstFormat::Tree),
+            "json" => Some(AstFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+pub struct AstPrinter {
+    format: AstFormat,
+}
+
+impl AstPrinter {
+    pub fn new(format: AstFormat) -> Self {
+        AstPrinter { format }
+    }
+
+    pub fn print(&self, statements: &[Stmt]) -> String {
+        let mut lines = vec![];
+        for statement in statements {
+            match self.format {
+                AstFormat::Lisp => lines.push(self.lisp_stmt(statement)),
+                AstFormat::Tree => self.tree_stmt(statement, 0, &mut lines),
+                AstFormat::Json => return to_json(statements),
+            }
+        }
+        lines.join("\n")
+    }
+
+    pub fn print_expr(&self, expr: &Expr) -> String {
+        match self.format {
+            AstFormat::Json => to_json(expr),
+            AstFormat::Lisp | AstFormat::Tree => self.lisp_expr(expr),
+        }
+    }
+
+    fn lisp_expr(&self, expr: &Expr) -> String {
+        match expr {
+            Expr::Assign { name, value } => {
+                format!("(= {} {})", name.lexeme, self.lisp_expr(value))
+            }
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            }
+            | Expr::Logical {
+                left,
+                operator,
+                right,
+            } => self.parenthesize(&operator.lexeme, &[left, right]),
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                let mut exprs = vec![callee.as_ref()];
+                exprs.extend(arguments.iter());
+                self.parenthesize("call", &exprs)
+            }
+            Expr::Get { object, name } => {
+                format!("(. {} {})", self.lisp_expr(object), name.lexeme)
+            }
+            Expr::Grouping { expression } => self.parenthesize("group", &[expression]),
+            Expr::Literal { value } => match value {
+                Literal::Nil => "nil".to_string(),
+                Literal::Bool(value) => value.to_string(),
+                Literal::Number(value) => value.to_string(),
+                Literal::String(value) => format!("{:?}", value),
+            },
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => format!(
+                "(= (. {} {}) {})",
+                self.lisp_expr(object),
+                name.lexeme,
+                self.lisp_expr(value)
+            ),
+            Expr::Super { method, .. } => format!("(super {})", method.lexeme),
+            Expr::This { .. } => "this".to_string(),
+            Expr::Unary { operator, right } => self.parenthesize(&operator.lexeme, &[right]),
+            Expr::Variable { name } => name.lexeme.clone(),
+        }
+    }
+
+    fn parenthesize(&self, name: &str, exprs: &[&Expr]) -> String {
+        let mut parts = vec![name.to_string()];
+        parts.extend(exprs.iter().map(|expr| self.lisp_expr(expr)));
+        format!("({})", parts.join(" "))
+    }
+
+    fn signature(&self, function: &Function) -> String {
+        let params: Vec<&str> = function
+            .params
+            .iter()
+            .map(|param| param.lexeme.as_str())
+            .collect();
+        format!("{}({})", function.name.lexeme, params.join(", "))
+    }
+
+    fn lisp_block(&self, name: &str, statements: &[Stmt]) -> String {
+        let mut parts = vec![name.to_string()];
+        parts.extend(statements.iter().map(|statement| self.lisp_stmt(statement)));
+        format!("({})", parts.join(" "))
+    }
+
+    fn lisp_stmt(&self, statement: &Stmt) -> String {
+        match statement {
+            Stmt::Block { statements } => self.lisp_block("block", statements),
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                let mut parts = vec!["class".to_string(), name.lexeme.clone()];
+                if let Some(superclass) = superclass {
+                    parts.push(format!("< {}", self.lisp_expr(superclass)));
+                }
+                parts.extend(methods.iter().map(|method| {
+                    self.lisp_block(&format!("fun {}", self.signature(method)), &method.body)
+                }));
+                format!("({})", parts.join(" "))
+            }
+            Stmt::Expression { expression } => format!("(; {})", self.lisp_expr(expression)),
+            Stmt::Function(function) => {
+                self.lisp_block(&format!("fun {}", self.signature(function)), &function.body)
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => match else_branch {
+                Some(else_branch) => format!(
+                    "(if-else {} {} {})",
+                    self.lisp_expr(condition),
+                    self.lisp_stmt(then_branch),
+                    self.lisp_stmt(else_branch)
+                ),
+                None => format!(
+                    "(if {} {})",
+                    self.lisp_expr(condition),
+                    self.lisp_stmt(then_branch)
+                ),
+            },
+            Stmt::Print { expression } => format!("(print {})", self.lisp_expr(expression)),
+            Stmt::Return { value, .. } => match value {
+                Some(value) => format!("(return {})", self.lisp_expr(value)),
+                None => "(return)".to_string(),
+            },
+            Stmt::Var { name, initializer } => match initializer {
+                Some(initializer) => {
+                    format!("(var {} {})", name.lexeme, self.lisp_expr(initializer))
+                }
+                None => format!("(var {})", name.lexeme),
+            },
+            Stmt::While { condition, body } => format!(
+                "(while {} {})",
+                self.lisp_expr(condition),
+                self.lisp_stmt(body)
+            ),
+        }
+    }
+
+    fn tree_line(&self, depth: usize, text: &str, lines: &mut Vec<String>) {
+        lines.push(format!("{}{}", "  ".repeat(depth), text));
+    }
+
+    fn tree_stmt(&self, statement: &Stmt, depth: usize, lines: &mut Vec<String>) {
+        match statement {
+            Stmt::Block { statements } => {
+                self.tree_line(depth, "Block", lines);
+                for statement in statements {
+                    self.tree_stmt(statement, depth + 1, lines);
+                }
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                let header = match superclass {
+                    Some(superclass) => {
+                        format!("Class {} < {}", name.lexeme, self.lisp_expr(superclass))
+                    }
+                    None => format!("Class {}", name.lexeme),
+                };
+                self.tree_line(depth, &header, lines);
+                for method in methods {
+                    self.tree_function(method, depth + 1, lines);
+                }
+            }
+            Stmt::Expression { expression } => {
+                self.tree_line(depth, "Expression", lines);
+                self.tree_line(depth + 1, &self.lisp_expr(expression), lines);
+            }
+            Stmt::Function(function) => self.tree_function(function, depth, lines),
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.tree_line(depth, "If", lines);
+                self.tree_line(depth + 1, &self.lisp_expr(condition), lines);
+                self.tree_line(depth + 1, "Then", lines);
+                self.tree_stmt(then_branch, depth + 2, lines);
+                if let Some(else_branch) = else_branch {
+                    self.tree_line(depth + 1, "Else", lines);
+                    self.tree_stmt(else_branch, depth + 2, lines);
+                }
+            }
+            Stmt::Print { expression } => {
+                self.tree_line(depth, "Print", lines);
+                self.tree_line(depth + 1, &self.lisp_expr(expression), lines);
+            }
+            Stmt::Return { value, .. } => {
+                self.tree_line(depth, "Return", lines);
+                if let Some(value) = value {
+                    self.tree_line(depth + 1, &self.lisp_expr(value), lines);
+                }
+            }
+            Stmt::Var { name, initializer } => {
+                self.tree_line(depth, &format!("Var {}", name.lexeme), lines);
+                if let Some(initializer) = initializer {
+                    self.tree_line(depth + 1, &self.lisp_expr(initializer), lines);
+                }
+            }
+            Stmt::While { condition, body } => {
+                self.tree_line(depth, "While", lines);
+                self.tree_line(depth + 1, &self.lisp_expr(condition), lines);
+                self.tree_stmt(body, depth + 1, lines);
+            }
+        }
+    }
+
+    fn tree_function(&self, function: &Function, depth: usize, lines: &mut Vec<String>) {
+        self.tree_line(depth, &format!("Fun {}", self.signature(function)), lines);
+        for statement in &function.body {
+            self.tree_stmt(statement, depth + 1, lines);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::*;
+    use crate::scanner::*;
+
+    fn parse(code: &str) -> Vec<Stmt> {
+        let mut scanner = Scanner::new(code.to_string());
+        scanner.scan().unwrap();
+        Parser::new(scanner.tokens).parse().unwrap()
+    }
+
+    #[test]
+    fn print_expression_in_lisp_style() {
+        let mut scanner = Scanner::new("-123 * (45.67)".to_string());
+        scanner.scan().unwrap();
+        let expr = Parser::new(scanner.tokens).parse_expression().unwrap();
+        assert_eq!(
+            "(* (- 123) (group 45.67))",
+            AstPrinter::new(AstFormat::Lisp).print_expr(&expr)
+        );
+    }
+
+    #[test]
+    fn print_statements_in_lisp_style() {
+        let statements = parse(
+            r#"var a = "hi"; a.b = f(1, 2);
+if (a and !b) print a; else { return; }"#,
+        );
+        let expected = r#"(var a "hi")
+(; (= (. a b) (call f 1 2)))
+(if-else (and a (! b)) (print a) (block (return)))"#;
+        assert_eq!(
+            expected,
+            AstPrinter::new(AstFormat::Lisp).print(&statements)
+        );
+    }
+
+    #[test]
+    fn print_statements_as_tree() {
+        let statements = parse(
+            "class A < B { m(x, y) { return x + y; } }
+fun f() { while (true) print this.a; }
+var c;",
+        );
+        let expected = "Class A < B
+  Fun m(x, y)
+    Return
+      (+ x y)
+Fun f()
+  While
+    true
+    Print
+      (. this a)
+Var c";
+        assert_eq!(
+            expected,
+            AstPrinter::new(AstFormat::Tree).print(&statements)
+        );
+    }
+
+    #[test]
+    fn print_statements_as_json() {
+        let statements = parse("print -x;");
+        let expected = r#"[
+  {
+    "type": "Print",
+    "expression": {
+      "type": "Unary",
+      "operator": {
+        "token_type": {
+          "type": "Minus"
+        },
+        "lexeme": "-",
+        "line": 1,
+        "span": {
+          "line": 1,
+          "column": 7,
+          "length": 1
+        }
+      },
+      "right": {
+        "type": "Variable",
+        "name": {
+          "token_type": {
+            "type": "Identifier",
+            "value": "x"
+          },
+          "lexeme": "x",
+          "line": 1,
+          "span": {
+            "line": 1,
+            "column": 8,
+            "length": 1
+          }
+        }
+      }
+    }
+  }
+]"#;
+        assert_eq!(
+            expected,
+            AstPrinter::new(AstFormat::Json).print(&statements)
+        );
+    }
+
+    #[test]
+    fn print_literals_as_json() {
+        let mut scanner = Scanner::new("nil".to_string());
+        scanner.scan().unwrap();
+        let expr = Parser::new(scanner.tokens).parse_expression().unwrap();
+        assert_eq!(
+            "{\n  \"type\": \"Literal\",\n  \"value\": {\n    \"type\": \"Nil\"\n  }\n}",
+            AstPrinter::new(AstFormat::Json).print_expr(&expr)
+        );
+    }
+}
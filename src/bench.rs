@@ -0,0 +1,175 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::scanner::Scanner;
+use crate::vm::{Options, Vm};
+
+// The scanner is timed over the script repeated to at least this many bytes,
+// so that small scripts still give a stable throughput.
+const SCAN_INPUT_BYTES: usize = 1024 * 1024;
+
+pub const DEFAULT_RUNS: usize = 5;
+
+pub const HEADER: &str =
+    "benchmark                   runs         min        mean         max   throughput";
+
+// Timings of the runs of one benchmark.
+pub struct Measurement {
+    pub name: String,
+    pub runs: Vec<Duration>,
+    // Size of the input, for benchmarks that report a throughput.
+    pub bytes: Option<usize>,
+}
+
+impl Measurement {
+    pub fn min(&self) -> Duration {
+        self.runs.iter().copied().min().unwrap_or_default()
+    }
+
+    pub fn max(&self) -> Duration {
+        self.runs.iter().copied().max().unwrap_or_default()
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.runs.len() {
+            0 => Duration::ZERO,
+            len => self.runs.iter().sum::<Duration>() / len as u32,
+        }
+    }
+
+    // Megabytes per second in the fastest run.
+    pub fn throughput(&self) -> Option<f64> {
+        let seconds = self.min().as_secs_f64();
+        match self.bytes {
+            Some(bytes) if seconds > 0.0 => Some(bytes as f64 / (1024.0 * 1024.0) / seconds),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<26} {:>5} {:>11.2?} {:>11.2?} {:>11.2?}",
+            self.name,
+            self.runs.len(),
+            self.min(),
+            self.mean(),
+            self.max()
+        )?;
+        if let Some(throughput) = self.throughput() {
+            write!(f, " {:>7.1} MB/s", throughput)?;
+        }
+        Ok(())
+    }
+}
+
+fn time<F>(runs: usize, mut run: F) -> Result<Vec<Duration>, Vec<Error>>
+where
+    F: FnMut() -> Result<(), Vec<Error>>,
+{
+    (0..runs)
+        .map(|_| {
+            let start = Instant::now();
+            run()?;
+            Ok(start.elapsed())
+        })
+        .collect()
+}
+
+// Times `Scanner::scan` over the code, repeated to a large input.
+pub fn scan(name: &str, code: &str, runs: usize) -> Result<Measurement, Vec<Error>> {
+    let copies = SCAN_INPUT_BYTES.div_ceil(code.len().max(1));
+    let input = vec![code; copies].join("\n");
+    let runs = time(runs, || {
+        let mut scanner = Scanner::new(input.clone());
+        scanner.scan().map_err(|error| vec![error])
+    })?;
+    Ok(Measurement {
+        name: format!("{} (scan)", name),
+        runs,
+        bytes: Some(input.len()),
+    })
+}
+
+// Times compiling and running the code in a fresh VM, discarding what it prints.
+// A first run that is not timed checks that the script works.
+pub fn execute(
+    name: &str,
+    code: &str,
+    runs: usize,
+    options: Options,
+) -> Result<Measurement, Vec<Error>> {
+    let run = || {
+        let mut vm = Vm::with_output(Box::new(std::io::sink()));
+        vm.set_options(options);
+        vm.interpret(code.to_string())
+    };
+    run()?;
+    Ok(Measurement {
+        name: name.to_string(),
+        runs: time(runs, run)?,
+        bytes: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarize_runs() {
+        let measurement = Measurement {
+            name: "m".to_string(),
+            runs: vec![
+                Duration::from_millis(30),
+                Duration::from_millis(10),
+                Duration::from_millis(20),
+            ],
+            bytes: Some(1024 * 1024),
+        };
+        assert_eq!(Duration::from_millis(10), measurement.min());
+        assert_eq!(Duration::from_millis(20), measurement.mean());
+        assert_eq!(Duration::from_millis(30), measurement.max());
+        assert_eq!(Some(100.0), measurement.throughput());
+        assert_eq!(
+            "m                              3     10.00ms     20.00ms     30.00ms   100.0 MB/s",
+            measurement.to_string()
+        );
+        assert_eq!(HEADER.len(), measurement.to_string().len());
+    }
+
+    #[test]
+    fn time_scanning_and_execution() {
+        let scanned = scan("s", "print 1;", 2).unwrap();
+        assert_eq!("s (scan)", scanned.name);
+        assert_eq!(2, scanned.runs.len());
+        assert!(scanned.bytes.unwrap() >= SCAN_INPUT_BYTES);
+
+        let executed = execute("e", "print 1;", 3, Options::default()).unwrap();
+        assert_eq!(3, executed.runs.len());
+        assert_eq!(None, executed.throughput());
+
+        assert!(scan("s", "print @;", 1).is_err());
+        assert!(execute("e", "print -nil;", 1, Options::default()).is_err());
+    }
+
+    #[test]
+    fn run_the_benchmark_suite() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/benches");
+        let mut count = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|extension| extension == "lox") {
+                let code = std::fs::read_to_string(&path).unwrap();
+                let name = path.display().to_string();
+                let result = execute(&name, &code, 0, Options::default());
+                assert!(result.is_ok(), "{}: {:?}", name, result.err());
+                count += 1;
+            }
+        }
+        assert_eq!(6, count);
+    }
+}
@@ -0,0 +1,709 @@
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, OpCode};
+use crate::compiler::MAX_NESTING;
+use crate::error::Error;
+use crate::object::*;
+use crate::value::Value;
+
+// A compiled file is the magic header, the format version, a checksum of the
+// payload and the payload itself: the script function, with every function
+// nested in it written in place of its constant. Integers are big-endian.
+pub const MAGIC: &[u8; 4] = b"LOXC";
+pub const VERSION: u16 = 3;
+
+const HEADER_LEN: usize = 10;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+pub fn is_compiled(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn serialize(heap: &Heap, function: ObjRef) -> Vec<u8> {
+    let mut payload = vec![];
+    write_function(heap, function, &mut payload);
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_be_bytes());
+    bytes.extend_from_slice(&checksum(&payload).to_be_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+pub fn deserialize(heap: &mut Heap, bytes: &[u8]) -> Result<ObjRef, Error> {
+    if bytes.len() < HEADER_LEN || !is_compiled(bytes) {
+        return Err(error("Not a compiled Lox file."));
+    }
+    let version = u16::from_be_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+        return Err(error(&format!(
+            "Unsupported bytecode version {} (expected {}).",
+            version, VERSION
+        )));
+    }
+    let expected = u32::from_be_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+    let payload = &bytes[HEADER_LEN..];
+    if checksum(payload) != expected {
+        return Err(error("Checksum mismatch, the file is corrupt."));
+    }
+
+    let mut reader = Reader {
+        bytes: payload,
+        current: 0,
+    };
+    let function = reader.function(heap, 0)?;
+    if reader.current != payload.len() {
+        return Err(error("Unexpected data after the script."));
+    }
+    // The script runs as a closure with no upvalues to give it.
+    if heap.function(function).upvalue_count != 0 {
+        return Err(error("The script cannot have upvalues."));
+    }
+    Ok(function)
+}
+
+fn error(msg: &str) -> Error {
+    Error::Bytecode {
+        message: msg.to_string(),
+    }
+}
+
+// FNV-1a, which is enough to notice truncated or damaged files.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+fn write_u32(value: usize, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(value as u32).to_be_bytes());
+}
+
+fn write_string(string: &str, out: &mut Vec<u8>) {
+    write_u32(string.len(), out);
+    out.extend_from_slice(string.as_bytes());
+}
+
+fn write_function(heap: &Heap, function: ObjRef, out: &mut Vec<u8>) {
+    let function = heap.function(function);
+    write_u32(function.arity, out);
+    write_u32(function.upvalue_count, out);
+    match function.name {
+        Some(name) => {
+            out.push(1);
+            write_string(heap.string(name), out);
+        }
+        None => out.push(0),
+    }
+
+    let chunk = &function.chunk;
+    match &chunk.file {
+        Some(file) => {
+            out.push(1);
+            write_string(file, out);
+        }
+        None => out.push(0),
+    }
+    write_u32(chunk.code.len(), out);
+    out.extend_from_slice(&chunk.code);
+
+    // Consecutive bytes mostly share a line, so lines are stored as runs.
+    let mut runs: Vec<(u64, usize)> = vec![];
+    for line in chunk.lines.iter() {
+        match runs.last_mut() {
+            Some((last, count)) if last == line => *count += 1,
+            _ => runs.push((*line, 1)),
+        }
+    }
+    write_u32(runs.len(), out);
+    for (line, count) in runs {
+        out.extend_from_slice(&line.to_be_bytes());
+        write_u32(count, out);
+    }
+
+    // Caches start out empty, so only their number is stored.
+    write_u32(chunk.caches.len(), out);
+
+    write_u32(chunk.constants.len(), out);
+    for constant in chunk.constants.iter() {
+        if let Some(obj) = constant.as_obj() {
+            match heap.get(obj) {
+                Obj::String(string) => {
+                    out.push(TAG_STRING);
+                    write_string(string, out);
+                }
+                Obj::Function(_) => {
+                    out.push(TAG_FUNCTION);
+                    write_function(heap, obj, out);
+                }
+                other => unreachable!("unexpected constant {:?}", other),
+            }
+        } else if let Some(number) = constant.as_number() {
+            out.push(TAG_NUMBER);
+            out.extend_from_slice(&number.to_be_bytes());
+        } else {
+            out.push(match constant.as_bool() {
+                Some(true) => TAG_TRUE,
+                Some(false) => TAG_FALSE,
+                None => TAG_NIL,
+            });
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    current: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], Error> {
+        if self.bytes.len() - self.current < len {
+            return Err(error("Unexpected end of file."));
+        }
+        let bytes = &self.bytes[self.current..self.current + len];
+        self.current += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let len = self.u32()?;
+        let bytes = self.take(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| error("Invalid string constant."))
+    }
+
+    // Reads a function `depth` functions deep in the script, refusing to
+    // nest further than the compiler could.
+    fn function(&mut self, heap: &mut Heap, depth: usize) -> Result<ObjRef, Error> {
+        if depth > MAX_NESTING {
+            return Err(error("Functions are nested too deeply."));
+        }
+        let arity = self.u32()?;
+        let upvalue_count = self.u32()?;
+        let name = match self.u8()? {
+            0 => None,
+            _ => Some(heap.intern(&self.string()?)),
+        };
+
+        let mut chunk = Chunk::new();
+        chunk.file = match self.u8()? {
+            0 => None,
+            _ => Some(self.string()?.into()),
+        };
+        let len = self.u32()?;
+        chunk.code = self.take(len)?.to_vec();
+        for _ in 0..self.u32()? {
+            let line = self.u64()?;
+            let count = self.u32()?;
+            if chunk.lines.len() + count > chunk.code.len() {
+                return Err(error("Line information does not match the code."));
+            }
+            chunk.lines.extend(std::iter::repeat_n(line, count));
+        }
+        if chunk.lines.len() != chunk.code.len() {
+            return Err(error("Line information does not match the code."));
+        }
+
+        for _ in 0..self.u32()? {
+            chunk.add_cache();
+        }
+
+        for _ in 0..self.u32()? {
+            let constant = match self.u8()? {
+                TAG_NIL => Value::NIL,
+                TAG_FALSE => Value::bool(false),
+                TAG_TRUE => Value::bool(true),
+                TAG_NUMBER => Value::number(f64::from_bits(self.u64()?)),
+                TAG_STRING => Value::obj(heap.intern(&self.string()?)),
+                TAG_FUNCTION => Value::obj(self.function(heap, depth + 1)?),
+                tag => return Err(error(&format!("Unknown constant tag {}.", tag))),
+            };
+            chunk.constants.push(constant);
+        }
+
+        verify(heap, &chunk, arity, upvalue_count)?;
+        Ok(heap.alloc(Obj::Function(Function {
+            arity,
+            upvalue_count,
+            chunk: Rc::new(chunk),
+            name,
+        })))
+    }
+}
+
+// Checks that a loaded function only does what compiled code can do, so that
+// a damaged file is rejected here rather than crashing the VM. Every reachable
+// instruction must be known, with operands inside the code that name existing
+// constants of the right kind, caches, locals and upvalues. Jumps must stay in
+// the function, the stack must hold what each instruction takes from it, and
+// have the same height however an instruction is reached.
+fn verify(heap: &Heap, chunk: &Chunk, arity: usize, upvalue_count: usize) -> Result<(), Error> {
+    let code = &chunk.code;
+    // The stack height before each instruction reached so far. The callee
+    // and the arguments are in the first slots.
+    let mut heights: Vec<Option<usize>> = vec![None; code.len()];
+    let mut pending = vec![(0, arity + 1)];
+    while let Some((offset, height)) = pending.pop() {
+        if offset >= code.len() {
+            return Err(error("Code runs past the end of a function."));
+        }
+        match heights[offset] {
+            Some(seen) if seen == height => continue,
+            Some(_) => {
+                return Err(error(&format!(
+                    "Stack height differs between the paths to offset {}.",
+                    offset
+                )))
+            }
+            None => heights[offset] = Some(height),
+        }
+
+        let byte = |at: usize| {
+            code.get(at).copied().ok_or_else(|| {
+                error(&format!(
+                    "Instruction at offset {} is cut off by the end of the code.",
+                    offset
+                ))
+            })
+        };
+        let u16 = |at: usize| Ok::<_, Error>(u16::from_be_bytes([byte(at)?, byte(at + 1)?]));
+        let constant = |at: usize| {
+            let index = u16(at)? as usize;
+            chunk.constants.get(index).copied().ok_or_else(|| {
+                error(&format!(
+                    "Constant {} at offset {} is out of range.",
+                    index, offset
+                ))
+            })
+        };
+        let object = |at: usize, function: bool| {
+            let found = constant(at)?.as_obj().map(|obj| (obj, heap.get(obj)));
+            match found {
+                Some((obj, Obj::Function(_))) if function => Ok(obj),
+                Some((obj, Obj::String(_))) if !function => Ok(obj),
+                _ => Err(error(&format!(
+                    "Expected a {} constant at offset {}.",
+                    if function { "function" } else { "string" },
+                    offset
+                ))),
+            }
+        };
+        let cache = |at: usize| match u16(at)? as usize {
+            index if index < chunk.caches.len() => Ok(()),
+            index => Err(error(&format!(
+                "Cache {} at offset {} is out of range.",
+                index, offset
+            ))),
+        };
+        let slot = |at: usize, count: usize, kind: &str| match byte(at)? as usize {
+            index if index < count => Ok(()),
+            index => Err(error(&format!(
+                "{} {} at offset {} is out of range.",
+                kind, index, offset
+            ))),
+        };
+        let target = |forward: bool| {
+            let jump = u16(offset + 1)? as usize;
+            let target = if forward {
+                Some(offset + 3 + jump)
+            } else {
+                (offset + 3).checked_sub(jump)
+            };
+            target
+                .filter(|target| *target < code.len())
+                .ok_or_else(|| error(&format!("Jump at offset {} leaves the function.", offset)))
+        };
+
+        let op = OpCode::from_byte(code[offset]).ok_or_else(|| {
+            error(&format!(
+                "Unknown opcode {} at offset {}.",
+                code[offset], offset
+            ))
+        })?;
+        // What the instruction takes from the stack and puts back, and where
+        // execution goes next.
+        let (pops, pushes, next) = match op {
+            OpCode::Constant => {
+                constant(offset + 1)?;
+                (0, 1, vec![offset + 3])
+            }
+            OpCode::Nil | OpCode::True | OpCode::False => (0, 1, vec![offset + 1]),
+            OpCode::Pop | OpCode::Print | OpCode::CloseUpvalue => (1, 0, vec![offset + 1]),
+            OpCode::GetLocal | OpCode::SetLocal => {
+                slot(offset + 1, height, "Local")?;
+                let pops = if op == OpCode::SetLocal { 1 } else { 0 };
+                (pops, 1, vec![offset + 2])
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                slot(offset + 1, upvalue_count, "Upvalue")?;
+                let pops = if op == OpCode::SetUpvalue { 1 } else { 0 };
+                (pops, 1, vec![offset + 2])
+            }
+            OpCode::GetGlobal | OpCode::Class => {
+                object(offset + 1, false)?;
+                (0, 1, vec![offset + 3])
+            }
+            OpCode::DefineGlobal => {
+                object(offset + 1, false)?;
+                (1, 0, vec![offset + 3])
+            }
+            OpCode::SetGlobal => {
+                object(offset + 1, false)?;
+                (1, 1, vec![offset + 3])
+            }
+            OpCode::GetSuper | OpCode::Method => {
+                object(offset + 1, false)?;
+                (2, 1, vec![offset + 3])
+            }
+            OpCode::GetProperty | OpCode::SetProperty => {
+                object(offset + 1, false)?;
+                cache(offset + 3)?;
+                let pops = if op == OpCode::SetProperty { 2 } else { 1 };
+                (pops, 1, vec![offset + 5])
+            }
+            OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Inherit => (2, 1, vec![offset + 1]),
+            OpCode::Not | OpCode::Negate => (1, 1, vec![offset + 1]),
+            OpCode::Jump => (0, 0, vec![target(true)?]),
+            OpCode::JumpIfFalse => (1, 1, vec![offset + 3, target(true)?]),
+            OpCode::Loop => (0, 0, vec![target(false)?]),
+            OpCode::Call => (byte(offset + 1)? as usize + 1, 1, vec![offset + 2]),
+            OpCode::Invoke => {
+                object(offset + 1, false)?;
+                cache(offset + 4)?;
+                (byte(offset + 3)? as usize + 1, 1, vec![offset + 6])
+            }
+            // The superclass is on top of the receiver and the arguments.
+            OpCode::SuperInvoke => {
+                object(offset + 1, false)?;
+                (byte(offset + 3)? as usize + 2, 1, vec![offset + 4])
+            }
+            OpCode::Closure => {
+                let function = object(offset + 1, true)?;
+                let mut at = offset + 3;
+                for _ in 0..heap.function(function).upvalue_count {
+                    match byte(at)? {
+                        1 => slot(at + 1, height, "Local")?,
+                        0 => slot(at + 1, upvalue_count, "Upvalue")?,
+                        _ => return Err(error(&format!("Invalid upvalue at offset {}.", offset))),
+                    }
+                    at += 2;
+                }
+                (0, 1, vec![at])
+            }
+            OpCode::Return => (1, 0, vec![]),
+        };
+        if height < pops {
+            return Err(error(&format!(
+                "Instruction at offset {} takes more values than the stack holds.",
+                offset
+            )));
+        }
+        let height = height - pops + pushes;
+        pending.extend(next.into_iter().map(|next| (next, height)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::*;
+    use crate::disassembler::*;
+    use crate::optimizer::OptLevel;
+    use crate::scanner::*;
+
+    fn compile(heap: &mut Heap, code: &str) -> ObjRef {
+        let mut scanner = Scanner::new(code.to_string());
+        scanner.scan().unwrap();
+        Compiler::new(heap, scanner.tokens)
+            .with_file_name("test.lox")
+            .compile()
+            .unwrap()
+    }
+
+    fn message(result: Result<ObjRef, Error>) -> String {
+        match result {
+            Err(Error::Bytecode { message }) => message,
+            other => panic!("expected a bytecode error, got {:?}", other),
+        }
+    }
+
+    fn function(
+        heap: &mut Heap,
+        upvalue_count: usize,
+        code: &[u8],
+        constants: Vec<Value>,
+    ) -> ObjRef {
+        let mut chunk = Chunk::new();
+        for byte in code {
+            chunk.write(*byte, 1);
+        }
+        chunk.constants = constants;
+        chunk.add_cache();
+        heap.alloc(Obj::Function(Function {
+            arity: 0,
+            upvalue_count,
+            chunk: Rc::new(chunk),
+            name: None,
+        }))
+    }
+
+    // Loads a well-formed file holding a script with `code`, so that only the
+    // checks of the code itself can reject it. The constants are a number and
+    // a string.
+    fn load(code: &[u8]) -> Result<ObjRef, Error> {
+        let mut heap = Heap::new();
+        let constants = vec![Value::number(1.0), Value::obj(heap.intern("name"))];
+        let script = function(&mut heap, 0, code, constants);
+        deserialize(&mut Heap::new(), &serialize(&heap, script))
+    }
+
+    #[test]
+    fn functions_round_trip() {
+        let code = "fun outer(a) {
+  var x = \"s\";
+  fun inner() { return x + nil; }
+  return inner;
+}
+print outer(1.5)() == true;";
+        let mut heap = Heap::new();
+        let function = compile(&mut heap, code);
+        let bytes = serialize(&heap, function);
+        assert!(is_compiled(&bytes));
+
+        let mut loaded_heap = Heap::new();
+        let loaded = deserialize(&mut loaded_heap, &bytes).unwrap();
+        assert_eq!(
+            Disassembler::new(&heap).disassemble(function),
+            Disassembler::new(&loaded_heap).disassemble(loaded)
+        );
+        assert_eq!(
+            heap.function(function).chunk.lines,
+            loaded_heap.function(loaded).chunk.lines
+        );
+        assert_eq!(
+            Some("test.lox"),
+            loaded_heap.function(loaded).chunk.file.as_deref()
+        );
+    }
+
+    #[test]
+    fn compiled_benchmarks_pass_verification() {
+        let benches = concat!(env!("CARGO_MANIFEST_DIR"), "/benches");
+        for entry in std::fs::read_dir(benches).unwrap() {
+            let code = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            for opt_level in [OptLevel::O0, OptLevel::O1] {
+                let mut heap = Heap::new();
+                let mut scanner = Scanner::new(code.clone());
+                scanner.scan().unwrap();
+                let function = Compiler::new(&mut heap, scanner.tokens)
+                    .with_opt_level(opt_level)
+                    .compile()
+                    .unwrap();
+                deserialize(&mut Heap::new(), &serialize(&heap, function)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn reject_invalid_code() {
+        const NIL: u8 = OpCode::Nil as u8;
+        const RETURN: u8 = OpCode::Return as u8;
+        assert!(load(&[NIL, RETURN]).is_ok());
+        assert_eq!(
+            "Unknown opcode 200 at offset 1.",
+            message(load(&[NIL, 200, RETURN]))
+        );
+        assert_eq!(
+            "Instruction at offset 0 is cut off by the end of the code.",
+            message(load(&[OpCode::Constant as u8, 0]))
+        );
+        assert_eq!(
+            "Constant 1280 at offset 0 is out of range.",
+            message(load(&[OpCode::Constant as u8, 5, 0, RETURN]))
+        );
+        assert_eq!(
+            "Expected a string constant at offset 0.",
+            message(load(&[OpCode::GetGlobal as u8, 0, 0, RETURN]))
+        );
+        assert_eq!(
+            "Expected a function constant at offset 0.",
+            message(load(&[OpCode::Closure as u8, 0, 1, RETURN]))
+        );
+        assert_eq!(
+            "Cache 1 at offset 1 is out of range.",
+            message(load(&[NIL, OpCode::GetProperty as u8, 0, 1, 0, 1, RETURN]))
+        );
+        assert_eq!(
+            "Jump at offset 0 leaves the function.",
+            message(load(&[OpCode::Jump as u8, 0, 9, NIL, RETURN]))
+        );
+        assert_eq!(
+            "Jump at offset 1 leaves the function.",
+            message(load(&[NIL, OpCode::Loop as u8, 0, 9, RETURN]))
+        );
+        assert_eq!(
+            "Local 3 at offset 0 is out of range.",
+            message(load(&[OpCode::GetLocal as u8, 3, RETURN]))
+        );
+        assert_eq!(
+            "Upvalue 0 at offset 0 is out of range.",
+            message(load(&[OpCode::GetUpvalue as u8, 0, RETURN]))
+        );
+        assert_eq!(
+            "Instruction at offset 1 takes more values than the stack holds.",
+            message(load(&[OpCode::Pop as u8, OpCode::Pop as u8, NIL, RETURN]))
+        );
+        assert_eq!(
+            "Code runs past the end of a function.",
+            message(load(&[NIL, OpCode::Print as u8]))
+        );
+        // Only one of the paths to the final Return pushes a value.
+        assert_eq!(
+            "Stack height differs between the paths to offset 5.",
+            message(load(&[
+                OpCode::True as u8,
+                OpCode::JumpIfFalse as u8,
+                0,
+                1,
+                NIL,
+                RETURN
+            ]))
+        );
+    }
+
+    #[test]
+    fn reject_invalid_upvalues() {
+        let mut heap = Heap::new();
+        let inner = function(
+            &mut heap,
+            1,
+            &[OpCode::Nil as u8, OpCode::Return as u8],
+            vec![],
+        );
+        let closure = |is_local: u8, index: u8| {
+            vec![
+                OpCode::Closure as u8,
+                0,
+                0,
+                is_local,
+                index,
+                OpCode::Return as u8,
+            ]
+        };
+        let load = |heap: &mut Heap, upvalue_count: usize, code: &[u8]| {
+            let script = function(heap, upvalue_count, code, vec![Value::obj(inner)]);
+            deserialize(&mut Heap::new(), &serialize(heap, script))
+        };
+
+        assert!(load(&mut heap, 0, &closure(1, 0)).is_ok());
+        assert_eq!(
+            "Invalid upvalue at offset 0.",
+            message(load(&mut heap, 0, &closure(2, 0)))
+        );
+        assert_eq!(
+            "Local 1 at offset 0 is out of range.",
+            message(load(&mut heap, 0, &closure(1, 1)))
+        );
+        assert_eq!(
+            "Upvalue 0 at offset 0 is out of range.",
+            message(load(&mut heap, 0, &closure(0, 0)))
+        );
+        assert_eq!(
+            "The script cannot have upvalues.",
+            message(load(&mut heap, 1, &closure(0, 0)))
+        );
+    }
+
+    #[test]
+    fn reject_invalid_files() {
+        let mut heap = Heap::new();
+        let function = compile(&mut heap, "print 1;");
+        let bytes = serialize(&heap, function);
+
+        assert_eq!(
+            "Not a compiled Lox file.",
+            message(deserialize(&mut heap, b"print 1;"))
+        );
+
+        let mut newer = bytes.clone();
+        newer[5] += 1;
+        assert_eq!(
+            "Unsupported bytecode version 4 (expected 3).",
+            message(deserialize(&mut heap, &newer))
+        );
+
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 0xff;
+        assert_eq!(
+            "Checksum mismatch, the file is corrupt.",
+            message(deserialize(&mut heap, &corrupt))
+        );
+
+        let mut truncated = bytes[..bytes.len() - 1].to_vec();
+        let sum = checksum(&truncated[HEADER_LEN..]).to_be_bytes();
+        truncated[6..HEADER_LEN].copy_from_slice(&sum);
+        assert_eq!(
+            "Unexpected end of file.",
+            message(deserialize(&mut heap, &truncated))
+        );
+    }
+
+    #[test]
+    fn reject_deeply_nested_functions() {
+        // A function with no code whose only constant is the next function.
+        let mut payload = vec![];
+        let depth = 200_000;
+        for _ in 0..depth {
+            payload.extend_from_slice(&[0; 8]);
+            payload.extend_from_slice(&[0, 0]);
+            payload.extend_from_slice(&[0; 12]);
+            payload.extend_from_slice(&1u32.to_be_bytes());
+            payload.push(TAG_FUNCTION);
+        }
+        payload.extend_from_slice(&[0; 8]);
+        payload.extend_from_slice(&[0, 0]);
+        payload.extend_from_slice(&[0; 16]);
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_be_bytes());
+        bytes.extend_from_slice(&checksum(&payload).to_be_bytes());
+        bytes.extend_from_slice(&payload);
+        assert_eq!(
+            "Functions are nested too deeply.",
+            message(deserialize(&mut Heap::new(), &bytes))
+        );
+
+        let mut heap = Heap::new();
+        let code = format!("{}{}", "fun f() {".repeat(100), "}".repeat(100));
+        let function = compile(&mut heap, &code);
+        assert!(deserialize(&mut Heap::new(), &serialize(&heap, function)).is_ok());
+    }
+}
@@ -0,0 +1,176 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::object::{ObjRef, ShapeId};
+use crate::value::Value;
+
+#[repr(u8)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OpCode {
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal,
+    SetLocal,
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    SetProperty,
+    GetSuper,
+    Equal,
+    Greater,
+    Less,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Invoke,
+    SuperInvoke,
+    Closure,
+    CloseUpvalue,
+    Return,
+    Class,
+    Inherit,
+    Method,
+}
+
+impl OpCode {
+    // Listed in discriminant order, so a byte indexes its own opcode.
+    const ALL: [OpCode; 37] = [
+        OpCode::Constant,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Pop,
+        OpCode::GetLocal,
+        OpCode::SetLocal,
+        OpCode::GetGlobal,
+        OpCode::DefineGlobal,
+        OpCode::SetGlobal,
+        OpCode::GetUpvalue,
+        OpCode::SetUpvalue,
+        OpCode::GetProperty,
+        OpCode::SetProperty,
+        OpCode::GetSuper,
+        OpCode::Equal,
+        OpCode::Greater,
+        OpCode::Less,
+        OpCode::Add,
+        OpCode::Subtract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Not,
+        OpCode::Negate,
+        OpCode::Print,
+        OpCode::Jump,
+        OpCode::JumpIfFalse,
+        OpCode::Loop,
+        OpCode::Call,
+        OpCode::Invoke,
+        OpCode::SuperInvoke,
+        OpCode::Closure,
+        OpCode::CloseUpvalue,
+        OpCode::Return,
+        OpCode::Class,
+        OpCode::Inherit,
+        OpCode::Method,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        OpCode::ALL.get(byte as usize).copied()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Value>,
+    pub lines: Vec<u64>,
+    // The file the code was compiled from, for stack traces.
+    pub file: Option<Rc<str>>,
+    // One per property access site, filled in as the code runs.
+    pub caches: Vec<Cell<InlineCache>>,
+}
+
+// What a property access site found on the last receiver it saw. It is only
+// reused while receivers keep the same shape.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum InlineCache {
+    #[default]
+    Empty,
+    // The field is stored in this slot.
+    Field {
+        shape: ShapeId,
+        slot: usize,
+    },
+    // Instances have no such field, and the method comes from their class.
+    Method {
+        shape: ShapeId,
+        method: ObjRef,
+    },
+    // Instances have no such field, and setting it moves them to the `to` shape.
+    Transition {
+        from: ShapeId,
+        to: ShapeId,
+    },
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        Chunk::default()
+    }
+
+    pub fn write(&mut self, byte: u8, line: u64) {
+        self.code.push(byte);
+        self.lines.push(line);
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+
+    pub fn add_cache(&mut self) -> usize {
+        self.caches.push(Cell::default());
+        self.caches.len() - 1
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcodes_round_trip_through_bytes() {
+        for (byte, op) in OpCode::ALL.iter().enumerate() {
+            assert_eq!(byte, *op as usize);
+            assert_eq!(Some(*op), OpCode::from_byte(byte as u8));
+        }
+        assert_eq!(None, OpCode::from_byte(OpCode::ALL.len() as u8));
+    }
+
+    #[test]
+    fn write_records_lines() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Nil as u8, 1);
+        chunk.write(OpCode::Return as u8, 2);
+        assert_eq!(vec![OpCode::Nil as u8, OpCode::Return as u8], chunk.code);
+        assert_eq!(vec![1, 2], chunk.lines);
+    }
+}
@@ -0,0 +1,1443 @@
+use std::rc::Rc;
+
+use crate::chunk::*;
+use crate::error::*;
+use crate::object::*;
+use crate::optimizer::{self, OptLevel};
+use crate::token::*;
+use crate::value::Value;
+
+const MAX_LOCALS: usize = 256;
+const MAX_UPVALUES: usize = 256;
+const MAX_ARGUMENTS: usize = 255;
+// How deeply statements, function bodies and expressions may nest, so that
+// the recursive descent cannot overflow the stack.
+pub(crate) const MAX_NESTING: usize = 255;
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+impl Precedence {
+    fn next(self) -> Precedence {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call | Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool);
+
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum FunctionKind {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+struct Local {
+    name: String,
+    // None until the variable's initializer has been compiled.
+    depth: Option<usize>,
+    is_captured: bool,
+}
+
+struct UpvalueRef {
+    index: u8,
+    is_local: bool,
+}
+
+struct FunctionState {
+    kind: FunctionKind,
+    name: Option<ObjRef>,
+    arity: usize,
+    chunk: Chunk,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueRef>,
+    scope_depth: usize,
+    // Offsets of the emitted instructions, so the optimizer can look back at them.
+    instructions: Vec<usize>,
+    // The latest offset a jump lands on. Instructions before it and after it
+    // are not always executed together, so they are never folded into one.
+    jump_target: usize,
+}
+
+impl FunctionState {
+    fn new(kind: FunctionKind, name: Option<ObjRef>) -> Self {
+        // Slot zero holds the receiver in methods and the called function otherwise.
+        let slot_zero = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            FunctionKind::Function | FunctionKind::Script => "",
+        };
+        FunctionState {
+            kind,
+            name,
+            arity: 0,
+            chunk: Chunk::new(),
+            locals: vec![Local {
+                name: slot_zero.to_string(),
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: vec![],
+            scope_depth: 0,
+            instructions: vec![],
+            jump_target: 0,
+        }
+    }
+}
+
+struct ClassState {
+    has_superclass: bool,
+}
+
+pub struct Compiler<'a> {
+    heap: &'a mut Heap,
+    tokens: Vec<Token>,
+    current: usize,
+    states: Vec<FunctionState>,
+    classes: Vec<ClassState>,
+    errors: Vec<Error>,
+    panic_mode: bool,
+    depth: usize,
+    // Set once the code nests too deeply. The rest of it is skipped, as the
+    // enclosing constructs would only report their missing ends.
+    too_deep: bool,
+    opt_level: OptLevel,
+    file: Option<Rc<str>>,
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(heap: &'a mut Heap, tokens: Vec<Token>) -> Self {
+        Compiler {
+            heap,
+            tokens,
+            current: 0,
+            states: vec![FunctionState::new(FunctionKind::Script, None)],
+            classes: vec![],
+            errors: vec![],
+            panic_mode: false,
+            depth: 0,
+            too_deep: false,
+            opt_level: OptLevel::default(),
+            file: None,
+        }
+    }
+
+    pub fn with_opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+
+    // Names the file the tokens come from in the compiled functions.
+    pub fn with_file_name(mut self, file_name: &str) -> Self {
+        self.file = Some(file_name.into());
+        self
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current]
+    }
+
+    fn previous(&self) -> &Token {
+        &self.tokens[self.current - 1]
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.peek().token_type == TokenType::EOF
+    }
+
+    fn advance(&mut self) {
+        if !self.is_at_end() {
+            self.current += 1;
+        }
+    }
+
+    fn check(&self, token_type: &TokenType) -> bool {
+        self.peek().token_type == *token_type
+    }
+
+    fn check_identifier(&self) -> bool {
+        matches!(self.peek().token_type, TokenType::Identifier(_))
+    }
+
+    fn match_next(&mut self, token_type: TokenType) -> bool {
+        if !self.check(&token_type) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn match_keyword(&mut self, keyword: Keyword) -> bool {
+        self.match_next(TokenType::Keyword(keyword))
+    }
+
+    fn consume(&mut self, token_type: TokenType, msg: &str) {
+        if self.check(&token_type) {
+            self.advance();
+        } else {
+            self.error_at_current(msg);
+        }
+    }
+
+    fn consume_identifier(&mut self, msg: &str) {
+        if self.check_identifier() {
+            self.advance();
+        } else {
+            self.error_at_current(msg);
+        }
+    }
+
+    // Resolve errors are about where names and keywords are used, the others
+    // about the grammar.
+    fn error_at(&mut self, index: usize, msg: &str, resolve: bool) {
+        // Only the first error of a statement is reported, the rest are likely cascades.
+        if self.panic_mode || self.too_deep {
+            return;
+        }
+        self.panic_mode = true;
+        let token = &self.tokens[index];
+        let span = token.span;
+        let lexeme = (token.token_type != TokenType::EOF).then(|| token.lexeme.clone());
+        let message = msg.to_string();
+        self.errors.push(if resolve {
+            Error::Resolve {
+                span,
+                lexeme,
+                message,
+            }
+        } else {
+            Error::Parse {
+                span,
+                lexeme,
+                message,
+            }
+        });
+    }
+
+    fn error(&mut self, msg: &str) {
+        self.error_at(self.current - 1, msg, false);
+    }
+
+    fn error_at_current(&mut self, msg: &str) {
+        self.error_at(self.current, msg, false);
+    }
+
+    fn resolve_error(&mut self, msg: &str) {
+        self.error_at(self.current - 1, msg, true);
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+        while !self.is_at_end() {
+            if self.previous().token_type == TokenType::Semicolon {
+                return;
+            }
+            match self.peek().token_type {
+                TokenType::Keyword(Keyword::Class)
+                | TokenType::Keyword(Keyword::Fun)
+                | TokenType::Keyword(Keyword::Var)
+                | TokenType::Keyword(Keyword::For)
+                | TokenType::Keyword(Keyword::If)
+                | TokenType::Keyword(Keyword::While)
+                | TokenType::Keyword(Keyword::Print)
+                | TokenType::Keyword(Keyword::Return) => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    // Compiles one level deeper, unless that is too deep.
+    fn nested(&mut self, compile: impl FnOnce(&mut Self)) {
+        if self.depth == MAX_NESTING {
+            self.error_at_current("Too much nesting.");
+            self.too_deep = true;
+            self.current = self.tokens.len() - 1;
+            return;
+        }
+        self.depth += 1;
+        compile(self);
+        self.depth -= 1;
+    }
+
+    fn state(&self) -> &FunctionState {
+        self.states.last().unwrap()
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.states.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().chunk
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.previous().line;
+        self.chunk().write(byte, line);
+    }
+
+    fn emit_op(&mut self, op: OpCode) {
+        if self.opt_level == OptLevel::O1 && self.fold(op) {
+            return;
+        }
+        let offset = self.chunk().code.len();
+        self.state_mut().instructions.push(offset);
+        self.emit_byte(op as u8);
+    }
+
+    // The value pushed by the instruction at `offset`, if it only pushes a constant.
+    fn constant_at(&self, offset: usize) -> Option<Value> {
+        let chunk = &self.state().chunk;
+        match OpCode::from_byte(chunk.code[offset])? {
+            OpCode::Constant => Some(chunk.constants[chunk.read_u16(offset + 1) as usize]),
+            OpCode::Nil => Some(Value::NIL),
+            OpCode::True => Some(Value::bool(true)),
+            OpCode::False => Some(Value::bool(false)),
+            _ => None,
+        }
+    }
+
+    // The constants pushed by the last `count` instructions, with the offset
+    // of the first of them, when nothing can jump in between.
+    fn last_constants(&self, count: usize) -> Option<(usize, Vec<Value>)> {
+        let state = self.state();
+        let offsets = state
+            .instructions
+            .get(state.instructions.len().checked_sub(count)?..)?;
+        if offsets[0] < state.jump_target {
+            return None;
+        }
+        let values = offsets
+            .iter()
+            .map(|offset| self.constant_at(*offset))
+            .collect::<Option<Vec<_>>>()?;
+        Some((offsets[0], values))
+    }
+
+    // Replaces the constant operands of `op` with its result, when it is known
+    // at compile time. Returns whether it did.
+    fn fold(&mut self, op: OpCode) -> bool {
+        let folded = optimizer::foldable_operands(op)
+            .and_then(|count| self.last_constants(count))
+            .and_then(|(start, operands)| {
+                optimizer::fold(self.heap, op, &operands).map(|value| (start, value))
+            });
+        match folded {
+            Some((start, value)) => {
+                self.discard_constants_from(start);
+                match value.as_bool() {
+                    Some(true) => self.emit_op(OpCode::True),
+                    Some(false) => self.emit_op(OpCode::False),
+                    None => self.emit_constant(value),
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Removes the code emitted from `start` on.
+    fn discard_from(&mut self, start: usize) {
+        let state = self.state_mut();
+        state.chunk.code.truncate(start);
+        state.chunk.lines.truncate(start);
+        while state
+            .instructions
+            .last()
+            .is_some_and(|offset| *offset >= start)
+        {
+            state.instructions.pop();
+        }
+        state.jump_target = state.jump_target.min(start);
+    }
+
+    // Removes the code emitted from `start` on, which only pushes constants,
+    // along with the constants it added last to the pool. Nothing else uses
+    // them, so a folded expression takes no more of the pool than its result.
+    fn discard_constants_from(&mut self, start: usize) {
+        let state = self.state();
+        let constants: Vec<usize> = state
+            .instructions
+            .iter()
+            .rev()
+            .take_while(|offset| **offset >= start)
+            .filter(|offset| state.chunk.code[**offset] == OpCode::Constant as u8)
+            .map(|offset| state.chunk.read_u16(offset + 1) as usize)
+            .collect();
+        self.discard_from(start);
+        let pool = &mut self.chunk().constants;
+        for constant in constants {
+            if constant + 1 == pool.len() {
+                pool.pop();
+            }
+        }
+    }
+
+    // Compiles a statement that can never run, for its errors only.
+    fn dead_statement(&mut self) {
+        let start = self.chunk().code.len();
+        self.statement();
+        self.discard_from(start);
+    }
+
+    // The truthiness of the condition just compiled, if it is a constant.
+    // The condition's code is removed, since it is no longer needed.
+    fn constant_condition(&mut self) -> Option<bool> {
+        if self.opt_level == OptLevel::O0 {
+            return None;
+        }
+        let (start, values) = self.last_constants(1)?;
+        self.discard_constants_from(start);
+        Some(!values[0].is_falsey())
+    }
+
+    // Offset of the next instruction, as the target of a backward jump.
+    fn loop_start(&mut self) -> usize {
+        let start = self.chunk().code.len();
+        self.state_mut().jump_target = start;
+        start
+    }
+
+    fn emit_u16(&mut self, value: u16) {
+        let [high, low] = value.to_be_bytes();
+        self.emit_byte(high);
+        self.emit_byte(low);
+    }
+
+    fn emit_op_u16(&mut self, op: OpCode, operand: u16) {
+        self.emit_op(op);
+        self.emit_u16(operand);
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_op(OpCode::Loop);
+        let offset = self.chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+        self.emit_u16(offset as u16);
+    }
+
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_op_u16(op, u16::MAX);
+        self.chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // The jump is relative to the instruction following its two operand bytes.
+        let jump = self.chunk().code.len() - offset - 2;
+        self.state_mut().jump_target = self.chunk().code.len();
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+        let [high, low] = (jump as u16).to_be_bytes();
+        self.chunk().code[offset] = high;
+        self.chunk().code[offset + 1] = low;
+    }
+
+    fn emit_return(&mut self) {
+        if self.state().kind == FunctionKind::Initializer {
+            self.emit_op(OpCode::GetLocal);
+            self.emit_byte(0);
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+        self.emit_op(OpCode::Return);
+    }
+
+    fn make_constant(&mut self, value: Value) -> u16 {
+        let constant = self.chunk().add_constant(value);
+        if constant > u16::MAX as usize {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+        constant as u16
+    }
+
+    fn emit_cache(&mut self) {
+        let cache = self.chunk().add_cache();
+        if cache > u16::MAX as usize {
+            self.error("Too many property accesses in one chunk.");
+        }
+        self.emit_u16(cache as u16);
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let constant = self.make_constant(value);
+        self.emit_op_u16(OpCode::Constant, constant);
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> u16 {
+        let string = self.heap.intern(name);
+        self.make_constant(Value::obj(string))
+    }
+
+    fn end_function(&mut self) -> (ObjRef, Vec<UpvalueRef>) {
+        self.emit_return();
+        let mut state = self.states.pop().unwrap();
+        state.chunk.file = self.file.clone();
+        let function = self.heap.alloc(Obj::Function(Function {
+            arity: state.arity,
+            upvalue_count: state.upvalues.len(),
+            chunk: Rc::new(state.chunk),
+            name: state.name,
+        }));
+        (function, state.upvalues)
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+        let depth = self.state().scope_depth;
+        while let Some(local) = self.state().locals.last() {
+            if local.depth.is_none_or(|local_depth| local_depth <= depth) {
+                break;
+            }
+            if local.is_captured {
+                self.emit_op(OpCode::CloseUpvalue);
+            } else {
+                self.emit_op(OpCode::Pop);
+            }
+            self.state_mut().locals.pop();
+        }
+    }
+
+    fn resolve_local(&mut self, state: usize, name: &str) -> Option<u8> {
+        let (slot, depth) = self.states[state]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)
+            .map(|(slot, local)| (slot, local.depth))?;
+        if depth.is_none() {
+            self.resolve_error("Can't read local variable in its own initializer.");
+        }
+        Some(slot as u8)
+    }
+
+    fn add_upvalue(&mut self, state: usize, index: u8, is_local: bool) -> u8 {
+        let upvalues = &self.states[state].upvalues;
+        if let Some(existing) = upvalues
+            .iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local)
+        {
+            return existing as u8;
+        }
+        if upvalues.len() == MAX_UPVALUES {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+        self.states[state]
+            .upvalues
+            .push(UpvalueRef { index, is_local });
+        (self.states[state].upvalues.len() - 1) as u8
+    }
+
+    fn resolve_upvalue(&mut self, state: usize, name: &str) -> Option<u8> {
+        if state == 0 {
+            return None;
+        }
+        if let Some(local) = self.resolve_local(state - 1, name) {
+            self.states[state - 1].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(state, local, true));
+        }
+        let upvalue = self.resolve_upvalue(state - 1, name)?;
+        Some(self.add_upvalue(state, upvalue, false))
+    }
+
+    fn add_local(&mut self, name: String) {
+        if self.state().locals.len() == MAX_LOCALS {
+            self.error("Too many local variables in function.");
+            return;
+        }
+        self.state_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    fn declare_variable(&mut self) {
+        let state = self.state();
+        if state.scope_depth == 0 {
+            return;
+        }
+        let name = self.previous().lexeme.clone();
+        let duplicate = state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= state.scope_depth))
+            .any(|local| local.name == name);
+        if duplicate {
+            self.resolve_error("Already a variable with this name in this scope.");
+        }
+        self.add_local(name);
+    }
+
+    fn parse_variable(&mut self, msg: &str) -> u16 {
+        self.consume_identifier(msg);
+        self.declare_variable();
+        if self.state().scope_depth > 0 {
+            return 0;
+        }
+        let name = self.previous().lexeme.clone();
+        self.identifier_constant(&name)
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+        let depth = state.scope_depth;
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: u16) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit_op_u16(OpCode::DefineGlobal, global);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut count = 0;
+        if !self.check(&TokenType::RightParenthesis) {
+            loop {
+                self.expression();
+                if count == MAX_ARGUMENTS {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                count += 1;
+                if !self.match_next(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParenthesis, "Expect ')' after arguments.");
+        count.min(MAX_ARGUMENTS) as u8
+    }
+
+    fn rule(token_type: &TokenType) -> ParseRule<'a> {
+        let (prefix, infix, precedence): (Option<ParseFn<'a>>, Option<ParseFn<'a>>, Precedence) =
+            match token_type {
+                TokenType::LeftParenthesis => {
+                    (Some(Self::grouping), Some(Self::call), Precedence::Call)
+                }
+                TokenType::Dot => (None, Some(Self::dot), Precedence::Call),
+                TokenType::Minus => (Some(Self::unary), Some(Self::binary), Precedence::Term),
+                TokenType::Plus => (None, Some(Self::binary), Precedence::Term),
+                TokenType::Slash | TokenType::Star => {
+                    (None, Some(Self::binary), Precedence::Factor)
+                }
+                TokenType::Bang => (Some(Self::unary), None, Precedence::None),
+                TokenType::BangEqual | TokenType::EqualEqual => {
+                    (None, Some(Self::binary), Precedence::Equality)
+                }
+                TokenType::Greater
+                | TokenType::GreaterEqual
+                | TokenType::Less
+                | TokenType::LessEqual => (None, Some(Self::binary), Precedence::Comparison),
+                TokenType::Identifier(_) => (Some(Self::variable), None, Precedence::None),
+                TokenType::String(_) => (Some(Self::string), None, Precedence::None),
+                TokenType::Number(_) => (Some(Self::number), None, Precedence::None),
+                TokenType::Keyword(Keyword::And) => (None, Some(Self::and), Precedence::And),
+                TokenType::Keyword(Keyword::Or) => (None, Some(Self::or), Precedence::Or),
+                TokenType::Keyword(Keyword::False)
+                | TokenType::Keyword(Keyword::True)
+                | TokenType::Keyword(Keyword::Nil) => (Some(Self::literal), None, Precedence::None),
+                TokenType::Keyword(Keyword::Super) => (Some(Self::super_), None, Precedence::None),
+                TokenType::Keyword(Keyword::This) => (Some(Self::this), None, Precedence::None),
+                _ => (None, None, Precedence::None),
+            };
+        ParseRule {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.nested(|compiler| {
+            compiler.advance();
+            let prefix = match Self::rule(&compiler.previous().token_type).prefix {
+                Some(prefix) => prefix,
+                None => {
+                    compiler.error("Expect expression.");
+                    return;
+                }
+            };
+            let can_assign = precedence <= Precedence::Assignment;
+            prefix(compiler, can_assign);
+
+            while precedence <= Self::rule(&compiler.peek().token_type).precedence {
+                compiler.advance();
+                if let Some(infix) = Self::rule(&compiler.previous().token_type).infix {
+                    infix(compiler, can_assign);
+                }
+            }
+
+            if can_assign && compiler.match_next(TokenType::Equal) {
+                compiler.error("Invalid assignment target.");
+            }
+        });
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightParenthesis, "Expect ')' after expression.");
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let count = self.argument_list();
+        self.emit_op(OpCode::Call);
+        self.emit_byte(count);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume_identifier("Expect property name after '.'.");
+        let name = self.previous().lexeme.clone();
+        let name = self.identifier_constant(&name);
+        if can_assign && self.match_next(TokenType::Equal) {
+            self.expression();
+            self.emit_op_u16(OpCode::SetProperty, name);
+            self.emit_cache();
+        } else if self.match_next(TokenType::LeftParenthesis) {
+            let count = self.argument_list();
+            self.emit_op_u16(OpCode::Invoke, name);
+            self.emit_byte(count);
+            self.emit_cache();
+        } else {
+            self.emit_op_u16(OpCode::GetProperty, name);
+            self.emit_cache();
+        }
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let operator = self.previous().token_type.clone();
+        self.parse_precedence(Precedence::Unary);
+        match operator {
+            TokenType::Bang => self.emit_op(OpCode::Not),
+            TokenType::Minus => self.emit_op(OpCode::Negate),
+            _ => unreachable!(),
+        }
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
+        let operator = self.previous().token_type.clone();
+        self.parse_precedence(Self::rule(&operator).precedence.next());
+        match operator {
+            TokenType::BangEqual => {
+                self.emit_op(OpCode::Equal);
+                self.emit_op(OpCode::Not);
+            }
+            TokenType::EqualEqual => self.emit_op(OpCode::Equal),
+            TokenType::Greater => self.emit_op(OpCode::Greater),
+            TokenType::GreaterEqual => {
+                self.emit_op(OpCode::Less);
+                self.emit_op(OpCode::Not);
+            }
+            TokenType::Less => self.emit_op(OpCode::Less),
+            TokenType::LessEqual => {
+                self.emit_op(OpCode::Greater);
+                self.emit_op(OpCode::Not);
+            }
+            TokenType::Plus => self.emit_op(OpCode::Add),
+            TokenType::Minus => self.emit_op(OpCode::Subtract),
+            TokenType::Star => self.emit_op(OpCode::Multiply),
+            TokenType::Slash => self.emit_op(OpCode::Divide),
+            _ => unreachable!(),
+        }
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        if let TokenType::Number(number) = self.previous().token_type {
+            self.emit_constant(Value::number(number));
+        }
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        if let TokenType::String(string) = &self.previous().token_type {
+            let string = self.heap.intern(&string.clone());
+            self.emit_constant(Value::obj(string));
+        }
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.previous().token_type {
+            TokenType::Keyword(Keyword::False) => self.emit_op(OpCode::False),
+            TokenType::Keyword(Keyword::True) => self.emit_op(OpCode::True),
+            TokenType::Keyword(Keyword::Nil) => self.emit_op(OpCode::Nil),
+            _ => unreachable!(),
+        }
+    }
+
+    fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.parse_precedence(Precedence::And);
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(else_jump);
+        self.emit_op(OpCode::Pop);
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn named_variable(&mut self, name: &str, can_assign: bool) {
+        let state = self.states.len() - 1;
+        // Locals and upvalues take a one byte operand, globals a two byte constant index.
+        let (get_op, set_op, operand) = if let Some(slot) = self.resolve_local(state, name) {
+            (OpCode::GetLocal, OpCode::SetLocal, Err(slot))
+        } else if let Some(slot) = self.resolve_upvalue(state, name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, Err(slot))
+        } else {
+            let constant = self.identifier_constant(name);
+            (OpCode::GetGlobal, OpCode::SetGlobal, Ok(constant))
+        };
+        if can_assign && self.match_next(TokenType::Equal) {
+            self.expression();
+            self.emit_op(set_op);
+        } else {
+            self.emit_op(get_op);
+        }
+        match operand {
+            Ok(constant) => self.emit_u16(constant),
+            Err(slot) => self.emit_byte(slot),
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.previous().lexeme.clone();
+        self.named_variable(&name, can_assign);
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.resolve_error("Can't use 'this' outside of a class.");
+            return;
+        }
+        self.named_variable("this", false);
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.resolve_error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.resolve_error("Can't use 'super' in a class with no superclass.")
+            }
+            _ => {}
+        }
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume_identifier("Expect superclass method name.");
+        let name = self.previous().lexeme.clone();
+        let name = self.identifier_constant(&name);
+
+        self.named_variable("this", false);
+        if self.match_next(TokenType::LeftParenthesis) {
+            let count = self.argument_list();
+            self.named_variable("super", false);
+            self.emit_op_u16(OpCode::SuperInvoke, name);
+            self.emit_byte(count);
+        } else {
+            self.named_variable("super", false);
+            self.emit_op_u16(OpCode::GetSuper, name);
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            let returns = self.check(&TokenType::Keyword(Keyword::Return));
+            self.declaration();
+            if returns && self.opt_level == OptLevel::O1 {
+                // Nothing after a return in the same block can run.
+                let start = self.chunk().code.len();
+                while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+                    self.declaration();
+                }
+                self.discard_from(start);
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn function(&mut self, kind: FunctionKind) {
+        let name = self.previous().lexeme.clone();
+        let name = self.heap.intern(&name);
+        self.states.push(FunctionState::new(kind, Some(name)));
+        self.begin_scope();
+
+        self.consume(
+            TokenType::LeftParenthesis,
+            "Expect '(' after function name.",
+        );
+        if !self.check(&TokenType::RightParenthesis) {
+            loop {
+                self.state_mut().arity += 1;
+                if self.state().arity > MAX_ARGUMENTS {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                if !self.match_next(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParenthesis, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.nested(Self::block);
+
+        let (function, upvalues) = self.end_function();
+        let constant = self.make_constant(Value::obj(function));
+        self.emit_op_u16(OpCode::Closure, constant);
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume_identifier("Expect method name.");
+        let name = self.previous().lexeme.clone();
+        let constant = self.identifier_constant(&name);
+        let kind = if name == "init" {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        };
+        self.function(kind);
+        self.emit_op_u16(OpCode::Method, constant);
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume_identifier("Expect class name.");
+        let class_name = self.previous().lexeme.clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_op_u16(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+
+        if self.match_next(TokenType::Less) {
+            self.consume_identifier("Expect superclass name.");
+            self.variable(false);
+            if self.previous().lexeme == class_name {
+                self.resolve_error("A class can't inherit from itself.");
+            }
+
+            // The superclass lives in a local named 'super' of a scope around the methods.
+            self.begin_scope();
+            self.add_local("super".to_string());
+            self.define_variable(0);
+
+            self.named_variable(&class_name, false);
+            self.emit_op(OpCode::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        self.named_variable(&class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_op(OpCode::Pop);
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // A function may refer to itself, so it is initialized before its body is compiled.
+        self.mark_initialized();
+        self.function(FunctionKind::Function);
+        self.define_variable(global);
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+        if self.match_next(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+        self.define_variable(global);
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_op(OpCode::Pop);
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParenthesis, "Expect '(' after 'for'.");
+        if self.match_next(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.match_keyword(Keyword::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.loop_start();
+        let mut exit_jump = None;
+        if !self.match_next(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_op(OpCode::Pop);
+        }
+
+        if !self.match_next(TokenType::RightParenthesis) {
+            // The increment runs after the body, so the body jumps back to it.
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.loop_start();
+            self.expression();
+            self.emit_op(OpCode::Pop);
+            self.consume(TokenType::RightParenthesis, "Expect ')' after for clauses.");
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_op(OpCode::Pop);
+        }
+        self.end_scope();
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParenthesis, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParenthesis, "Expect ')' after condition.");
+
+        // Only the branch a constant condition selects is kept.
+        if let Some(condition) = self.constant_condition() {
+            if condition {
+                self.statement();
+            } else {
+                self.dead_statement();
+            }
+            if self.match_keyword(Keyword::Else) {
+                if condition {
+                    self.dead_statement();
+                } else {
+                    self.statement();
+                }
+            }
+            return;
+        }
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.statement();
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_op(OpCode::Pop);
+        if self.match_keyword(Keyword::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_op(OpCode::Print);
+    }
+
+    fn return_statement(&mut self) {
+        if self.state().kind == FunctionKind::Script {
+            self.resolve_error("Can't return from top-level code.");
+        }
+        if self.match_next(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.state().kind == FunctionKind::Initializer {
+                self.resolve_error("Can't return a value from an initializer.");
+            }
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_op(OpCode::Return);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.loop_start();
+        self.consume(TokenType::LeftParenthesis, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParenthesis, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_op(OpCode::Pop);
+    }
+
+    fn declaration(&mut self) {
+        if self.match_keyword(Keyword::Class) {
+            self.class_declaration();
+        } else if self.match_keyword(Keyword::Fun) {
+            self.fun_declaration();
+        } else if self.match_keyword(Keyword::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn statement(&mut self) {
+        self.nested(|compiler| {
+            if compiler.match_keyword(Keyword::Print) {
+                compiler.print_statement();
+            } else if compiler.match_keyword(Keyword::For) {
+                compiler.for_statement();
+            } else if compiler.match_keyword(Keyword::If) {
+                compiler.if_statement();
+            } else if compiler.match_keyword(Keyword::Return) {
+                compiler.return_statement();
+            } else if compiler.match_keyword(Keyword::While) {
+                compiler.while_statement();
+            } else if compiler.match_next(TokenType::LeftBrace) {
+                compiler.begin_scope();
+                compiler.block();
+                compiler.end_scope();
+            } else {
+                compiler.expression_statement();
+            }
+        });
+    }
+
+    // Compiles the whole token stream into the function of the top-level script.
+    pub fn compile(mut self) -> Result<ObjRef, Vec<Error>> {
+        while !self.is_at_end() {
+            self.declaration();
+        }
+        let (function, _) = self.end_function();
+        if self.errors.is_empty() {
+            Ok(function)
+        } else {
+            Err(self.errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::*;
+
+    fn compile_with(code: &str, opt_level: OptLevel) -> Result<(Heap, ObjRef), Vec<Error>> {
+        let mut scanner = Scanner::new(code.to_string());
+        scanner.scan().unwrap();
+        let mut heap = Heap::new();
+        let function = Compiler::new(&mut heap, scanner.tokens)
+            .with_opt_level(opt_level)
+            .compile()?;
+        Ok((heap, function))
+    }
+
+    fn compile(code: &str) -> Result<(Heap, ObjRef), Vec<Error>> {
+        compile_with(code, OptLevel::O0)
+    }
+
+    fn optimized(code: &str) -> (Heap, Rc<Chunk>) {
+        let (heap, function) = compile_with(code, OptLevel::O1).unwrap();
+        let chunk = heap.function(function).chunk.clone();
+        (heap, chunk)
+    }
+
+    fn compile_errors(code: &str) -> Vec<String> {
+        match compile(code) {
+            Err(errors) => errors
+                .into_iter()
+                .map(|error| match error {
+                    Error::Parse { .. } | Error::Resolve { .. } => error.to_string(),
+                    _ => panic!("expected a compile error"),
+                })
+                .collect(),
+            Ok(_) => panic!("expected compile errors"),
+        }
+    }
+
+    #[test]
+    fn compile_expression_statement() {
+        let (heap, function) = compile("print 1 + 2;").unwrap();
+        let chunk = &heap.function(function).chunk;
+        let expected = vec![
+            OpCode::Constant as u8,
+            0,
+            0,
+            OpCode::Constant as u8,
+            0,
+            1,
+            OpCode::Add as u8,
+            OpCode::Print as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        assert_eq!(expected, chunk.code);
+        assert_eq!(
+            vec![Value::number(1.0), Value::number(2.0)],
+            chunk.constants
+        );
+    }
+
+    #[test]
+    fn fold_constant_expressions() {
+        let (_, chunk) = optimized("print -(1 + 2 * 3) < 0 == !nil;");
+        let expected = vec![
+            OpCode::True as u8,
+            OpCode::Print as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        assert_eq!(expected, chunk.code);
+
+        let (heap, chunk) = optimized("print \"a\" + \"b\" + \"c\";");
+        assert_eq!(OpCode::Constant as u8, chunk.code[0]);
+        assert_eq!(OpCode::Print as u8, chunk.code[3]);
+        let constant = chunk.constants[chunk.read_u16(1) as usize];
+        assert_eq!("abc", heap.format(constant));
+    }
+
+    #[test]
+    fn folding_frees_the_operand_constants() {
+        let (heap, chunk) = optimized("print 1 + 2 * 3; print \"a\" + \"b\";");
+        let constants: Vec<String> = chunk
+            .constants
+            .iter()
+            .map(|constant| heap.format(*constant))
+            .collect();
+        assert_eq!(vec!["7", "ab"], constants);
+
+        // Unfolded, the operands would not fit in the pool.
+        let code = "print 1 + 2 + 3;\n".repeat(25_000);
+        assert_eq!(25_000, optimized(&code).1.constants.len());
+        assert!(compile(&code).is_err());
+    }
+
+    #[test]
+    fn keep_operations_that_can_fail_or_are_jumped_into() {
+        let (_, chunk) = optimized("print \"a\" - 1;");
+        assert!(chunk.code.contains(&(OpCode::Subtract as u8)));
+        let (_, chunk) = optimized("print (nil or 1) + 2;");
+        assert!(chunk.code.contains(&(OpCode::Add as u8)));
+        let (_, chunk) = optimized("var a; print a + 1 + 2;");
+        assert_eq!(
+            2,
+            chunk
+                .code
+                .iter()
+                .filter(|op| **op == OpCode::Add as u8)
+                .count()
+        );
+    }
+
+    #[test]
+    fn remove_dead_branches_and_code_after_return() {
+        let (_, chunk) = optimized("if (false) print 1; else print 2;");
+        let expected = vec![
+            OpCode::Constant as u8,
+            0,
+            1,
+            OpCode::Print as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        assert_eq!(expected, chunk.code);
+        let (_, chunk) = optimized("if (\"yes\") { print 1; }");
+        assert_eq!(OpCode::Print as u8, chunk.code[3]);
+        assert!(!chunk.code.contains(&(OpCode::JumpIfFalse as u8)));
+
+        let (heap, function) =
+            compile_with("fun f() { return 1; print 2; }", OptLevel::O1).unwrap();
+        let script = heap.function(function);
+        let f = script
+            .chunk
+            .constants
+            .iter()
+            .find_map(|constant| match constant.as_obj() {
+                Some(obj) if matches!(heap.get(obj), Obj::Function(_)) => Some(obj),
+                _ => None,
+            });
+        let expected = vec![
+            OpCode::Constant as u8,
+            0,
+            0,
+            OpCode::Return as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        assert_eq!(expected, heap.function(f.unwrap()).chunk.code);
+    }
+
+    #[test]
+    fn dead_code_is_still_checked() {
+        let errors = compile_with("if (false) { var a = a; }", OptLevel::O1)
+            .err()
+            .unwrap();
+        assert_eq!(1, errors.len());
+    }
+
+    #[test]
+    fn compile_locals_use_stack_slots() {
+        let (heap, function) = compile("{ var a = 1; a = a; }").unwrap();
+        let chunk = &heap.function(function).chunk;
+        let expected = vec![
+            OpCode::Constant as u8,
+            0,
+            0,
+            OpCode::GetLocal as u8,
+            1,
+            OpCode::SetLocal as u8,
+            1,
+            OpCode::Pop as u8,
+            OpCode::Pop as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        assert_eq!(expected, chunk.code);
+    }
+
+    #[test]
+    fn compile_reports_every_error() {
+        let expected = vec![
+            "[line 1] Parse error at '=': Expect variable name.",
+            "[line 2] Parse error at '+': Expect expression.",
+            "[line 3] Parse error at '=': Invalid assignment target.",
+        ];
+        assert_eq!(
+            expected,
+            compile_errors("var = 1;\nprint +;\n1 = 2;\nprint 3;")
+        );
+    }
+
+    #[test]
+    fn compile_limits_nesting() {
+        let negations = format!("print {}1;", "-".repeat(50_000));
+        assert_eq!(
+            vec!["[line 1] Parse error at '-': Too much nesting."],
+            compile_errors(&negations)
+        );
+        let blocks = format!("{}{}", "{".repeat(300), "}".repeat(300));
+        assert_eq!(
+            vec!["[line 1] Parse error at '{': Too much nesting."],
+            compile_errors(&blocks)
+        );
+        let ifs = format!("{}print 1;", "if (true) ".repeat(300));
+        assert_eq!(
+            vec!["[line 1] Parse error at 'true': Too much nesting."],
+            compile_errors(&ifs)
+        );
+        let functions = format!("{}{}", "fun f() {".repeat(300), "}".repeat(300));
+        assert_eq!(
+            vec!["[line 1] Parse error at 'fun': Too much nesting."],
+            compile_errors(&functions)
+        );
+        assert!(compile(&format!("print {}1{};", "(".repeat(200), ")".repeat(200))).is_ok());
+    }
+
+    #[test]
+    fn compile_reports_scope_errors() {
+        assert_eq!(
+            vec![
+                "[line 1] Resolve error at 'a': Can't read local variable in its own initializer."
+            ],
+            compile_errors("{ var a = a; }")
+        );
+        assert_eq!(
+            vec!["[line 1] Resolve error at 'a': Already a variable with this name in this scope."],
+            compile_errors("{ var a; var a; }")
+        );
+        assert_eq!(
+            vec!["[line 1] Resolve error at 'return': Can't return from top-level code."],
+            compile_errors("return 1;")
+        );
+        assert_eq!(
+            vec!["[line 1] Resolve error at 'this': Can't use 'this' outside of a class."],
+            compile_errors("print this;")
+        );
+        assert_eq!(
+            vec!["[line 1] Resolve error at 'super': Can't use 'super' in a class with no superclass."],
+            compile_errors("class A { f() { super.f(); } }")
+        );
+        assert_eq!(
+            vec!["[line 1] Resolve error at 'A': A class can't inherit from itself."],
+            compile_errors("class A < A {}")
+        );
+        assert_eq!(
+            vec!["[line 1] Resolve error at 'return': Can't return a value from an initializer."],
+            compile_errors("class A { init() { return 1; } }")
+        );
+    }
+}
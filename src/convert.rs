@@ -0,0 +1,319 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::error::Error;
+use crate::object::*;
+use crate::value::Value;
+use crate::vm::Vm;
+
+// Lox has no collections, so collections become instances. A map is an
+// instance of the class `Map` with a field per key. A list is a chain of
+// instances of the class `List` with the fields `first` and `rest`, ending in
+// nil, which scripts can walk and build with classes of their own: any
+// instances with those fields read back as a list.
+
+// Rust values that can be passed to Lox.
+pub trait IntoLox {
+    fn into_lox(self, vm: &mut Vm) -> Value;
+}
+
+// Rust values that can be read from Lox values.
+pub trait FromLox: Sized {
+    fn from_lox(value: Value, vm: &Vm) -> Result<Self, Error>;
+}
+
+// The arguments of a call from Rust, as a tuple of values that go into Lox.
+pub trait IntoLoxArgs {
+    fn into_lox_args(self, vm: &mut Vm) -> Vec<Value>;
+}
+
+fn expected(what: &str, value: Value, vm: &Vm) -> Error {
+    Error::runtime(format!("Expected {} but got {}.", what, vm.format(value)))
+}
+
+impl IntoLox for Value {
+    fn into_lox(self, _vm: &mut Vm) -> Value {
+        self
+    }
+}
+
+impl FromLox for Value {
+    fn from_lox(value: Value, _vm: &Vm) -> Result<Self, Error> {
+        Ok(value)
+    }
+}
+
+impl IntoLox for () {
+    fn into_lox(self, _vm: &mut Vm) -> Value {
+        Value::NIL
+    }
+}
+
+// Reading into `()` discards the value, for calls made for their effect.
+impl FromLox for () {
+    fn from_lox(_value: Value, _vm: &Vm) -> Result<Self, Error> {
+        Ok(())
+    }
+}
+
+impl IntoLox for f64 {
+    fn into_lox(self, _vm: &mut Vm) -> Value {
+        Value::number(self)
+    }
+}
+
+impl FromLox for f64 {
+    fn from_lox(value: Value, vm: &Vm) -> Result<Self, Error> {
+        value
+            .as_number()
+            .ok_or_else(|| expected("a number", value, vm))
+    }
+}
+
+impl IntoLox for bool {
+    fn into_lox(self, _vm: &mut Vm) -> Value {
+        Value::bool(self)
+    }
+}
+
+impl FromLox for bool {
+    fn from_lox(value: Value, vm: &Vm) -> Result<Self, Error> {
+        value
+            .as_bool()
+            .ok_or_else(|| expected("a boolean", value, vm))
+    }
+}
+
+impl IntoLox for &str {
+    fn into_lox(self, vm: &mut Vm) -> Value {
+        Value::obj(vm.heap_mut().intern(self))
+    }
+}
+
+impl IntoLox for String {
+    fn into_lox(self, vm: &mut Vm) -> Value {
+        self.as_str().into_lox(vm)
+    }
+}
+
+impl FromLox for String {
+    fn from_lox(value: Value, vm: &Vm) -> Result<Self, Error> {
+        match value.as_obj().map(|obj| vm.heap().get(obj)) {
+            Some(Obj::String(string)) => Ok(string.clone()),
+            _ => Err(expected("a string", value, vm)),
+        }
+    }
+}
+
+impl<T: IntoLox> IntoLox for Option<T> {
+    fn into_lox(self, vm: &mut Vm) -> Value {
+        match self {
+            Some(value) => value.into_lox(vm),
+            None => Value::NIL,
+        }
+    }
+}
+
+impl<T: FromLox> FromLox for Option<T> {
+    fn from_lox(value: Value, vm: &Vm) -> Result<Self, Error> {
+        if value.is_nil() {
+            Ok(None)
+        } else {
+            T::from_lox(value, vm).map(Some)
+        }
+    }
+}
+
+// An instance of `class` with the given fields, in order.
+fn instance(vm: &mut Vm, class: ObjRef, fields: Vec<(&str, Value)>) -> Value {
+    let heap = vm.heap_mut();
+    let mut shape = heap.class(class).shape;
+    let mut values = vec![];
+    for (name, value) in fields {
+        let name = heap.intern(name);
+        shape = heap.shape_transition(shape, name);
+        values.push(value);
+    }
+    Value::obj(heap.alloc(Obj::Instance(Instance {
+        class,
+        shape,
+        fields: values,
+    })))
+}
+
+// The fields of an instance by name, or None for other values.
+fn fields(value: Value, vm: &Vm) -> Option<Vec<(&str, Value)>> {
+    let heap = vm.heap();
+    match heap.get(value.as_obj()?) {
+        Obj::Instance(instance) => Some(
+            heap.shape_names(instance.shape)
+                .iter()
+                .map(|name| heap.string(*name))
+                .zip(instance.fields.iter().copied())
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+impl<T: IntoLox> IntoLox for Vec<T> {
+    fn into_lox(self, vm: &mut Vm) -> Value {
+        let class = vm.list_class();
+        let items: Vec<Value> = self.into_iter().map(|item| item.into_lox(vm)).collect();
+        items.into_iter().rev().fold(Value::NIL, |rest, first| {
+            instance(vm, class, vec![("first", first), ("rest", rest)])
+        })
+    }
+}
+
+impl<T: FromLox> FromLox for Vec<T> {
+    fn from_lox(value: Value, vm: &Vm) -> Result<Self, Error> {
+        let mut items = vec![];
+        let mut list = value;
+        // A script can link a node back to an earlier one, which never ends.
+        let mut seen = HashSet::new();
+        while !list.is_nil() {
+            if !seen.insert(list.as_obj()) {
+                return Err(expected("a list", value, vm));
+            }
+            let fields = fields(list, vm).unwrap_or_default();
+            let field = |name| fields.iter().find(|(field, _)| *field == name);
+            match (field("first"), field("rest")) {
+                (Some((_, first)), Some((_, rest))) => {
+                    items.push(T::from_lox(*first, vm)?);
+                    list = *rest;
+                }
+                _ => return Err(expected("a list", value, vm)),
+            }
+        }
+        Ok(items)
+    }
+}
+
+impl<T: IntoLox> IntoLox for HashMap<String, T> {
+    fn into_lox(self, vm: &mut Vm) -> Value {
+        // Fields are added in the order of their names, so that maps with the
+        // same keys share a shape.
+        let mut entries: Vec<(String, T)> = self.into_iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let entries: Vec<(String, Value)> = entries
+            .into_iter()
+            .map(|(key, value)| (key, value.into_lox(vm)))
+            .collect();
+        let fields = entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), *value))
+            .collect();
+        let class = vm.map_class();
+        instance(vm, class, fields)
+    }
+}
+
+impl<T: FromLox> FromLox for HashMap<String, T> {
+    fn from_lox(value: Value, vm: &Vm) -> Result<Self, Error> {
+        fields(value, vm)
+            .ok_or_else(|| expected("an instance", value, vm))?
+            .into_iter()
+            .map(|(name, field)| Ok((name.to_string(), T::from_lox(field, vm)?)))
+            .collect()
+    }
+}
+
+macro_rules! tuple_args {
+    ($($arg:ident)*) => {
+        impl<$($arg: IntoLox),*> IntoLoxArgs for ($($arg,)*) {
+            #[allow(non_snake_case, unused_variables)]
+            fn into_lox_args(self, vm: &mut Vm) -> Vec<Value> {
+                let ($($arg,)*) = self;
+                vec![$($arg.into_lox(vm)),*]
+            }
+        }
+    };
+}
+
+tuple_args!();
+tuple_args!(A);
+tuple_args!(A B);
+tuple_args!(A B C);
+tuple_args!(A B C D);
+tuple_args!(A B C D E);
+tuple_args!(A B C D E F);
+
+impl IntoLoxArgs for Vec<Value> {
+    fn into_lox_args(self, _vm: &mut Vm) -> Vec<Value> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: IntoLox + FromLox>(value: T) -> T {
+        let mut vm = Vm::with_output(Box::new(std::io::sink()));
+        let lox = value.into_lox(&mut vm);
+        T::from_lox(lox, &vm).unwrap()
+    }
+
+    #[test]
+    fn values_round_trip() {
+        assert_eq!(1.5, round_trip(1.5));
+        assert!(round_trip(true));
+        assert_eq!("lox", round_trip("lox".to_string()));
+        assert_eq!(None, round_trip(None::<f64>));
+        assert_eq!(Some(2.0), round_trip(Some(2.0)));
+        assert_eq!(vec![1.0, 2.0, 3.0], round_trip(vec![1.0, 2.0, 3.0]));
+        assert_eq!(Vec::<bool>::new(), round_trip(Vec::<bool>::new()));
+
+        let map = HashMap::from([
+            ("b".to_string(), vec!["x".to_string()]),
+            ("a".to_string(), vec![]),
+        ]);
+        assert_eq!(map, round_trip(map.clone()));
+    }
+
+    #[test]
+    fn collections_become_instances() {
+        let mut vm = Vm::with_output(Box::new(std::io::sink()));
+        let list = vec![1.0, 2.0].into_lox(&mut vm);
+        assert_eq!("List instance", vm.format(list));
+        let map = HashMap::from([("a".to_string(), true)]).into_lox(&mut vm);
+        assert_eq!("Map instance", vm.format(map));
+
+        let other = HashMap::from([("a".to_string(), false)]).into_lox(&mut vm);
+        let shape = |value: Value| vm.heap().instance(value.as_obj().unwrap()).shape;
+        assert_eq!(shape(map), shape(other));
+    }
+
+    #[test]
+    fn report_mismatched_values() {
+        let mut vm = Vm::with_output(Box::new(std::io::sink()));
+        let string = "one".into_lox(&mut vm);
+        let message = |result: Result<f64, Error>| match result {
+            Err(Error::Runtime {
+                line: 0, message, ..
+            }) => message,
+            other => panic!("unexpected result {:?}", other),
+        };
+        assert_eq!(
+            "Expected a number but got one.",
+            message(f64::from_lox(string, &vm))
+        );
+        assert!(bool::from_lox(Value::NIL, &vm).is_err());
+        assert!(String::from_lox(Value::number(1.0), &vm).is_err());
+        assert!(Vec::<f64>::from_lox(string, &vm).is_err());
+        let strings = vec!["a"].into_lox(&mut vm);
+        assert!(Vec::<f64>::from_lox(strings, &vm).is_err());
+        vm.interpret("class Node { init() { this.first = 1; this.rest = this; } }".to_string())
+            .unwrap();
+        let node = vm.get_global("Node").unwrap();
+        let cycle = vm.call_function(node, &[]).unwrap();
+        assert_eq!(
+            "Expected a list but got Node instance.",
+            match Vec::<f64>::from_lox(cycle, &vm) {
+                Err(Error::Runtime { message, .. }) => message,
+                other => panic!("unexpected result {:?}", other),
+            }
+        );
+        assert!(HashMap::<String, f64>::from_lox(Value::bool(true), &vm).is_err());
+    }
+}
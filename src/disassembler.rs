@@ -0,0 +1,247 @@
+use std::fmt::Write;
+
+use crate::chunk::*;
+use crate::object::*;
+use crate::value::Value;
+
+pub struct Disassembler<'a> {
+    heap: &'a Heap,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(heap: &'a Heap) -> Self {
+        Disassembler { heap }
+    }
+
+    // Disassembles the function and, after it, every function nested in it.
+    pub fn disassemble(&self, function: ObjRef) -> String {
+        let mut out = String::new();
+        let mut pending = vec![function];
+        while let Some(function) = pending.pop() {
+            let chunk = &self.heap.function(function).chunk;
+            let name = self.heap.format(Value::obj(function));
+            out.push_str(&self.disassemble_chunk(chunk, &name));
+            for constant in chunk.constants.iter().rev() {
+                if let Some(obj) = constant.as_obj() {
+                    if let Obj::Function(_) = self.heap.get(obj) {
+                        pending.push(obj);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    pub fn disassemble_chunk(&self, chunk: &Chunk, name: &str) -> String {
+        let mut out = format!("== {} ==\n", name);
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            offset = self.disassemble_instruction(chunk, offset, &mut out);
+        }
+        out
+    }
+
+    // Appends the instruction at `offset` to `out` and returns the offset of the next one.
+    pub fn disassemble_instruction(&self, chunk: &Chunk, offset: usize, out: &mut String) -> usize {
+        write!(out, "{:04} ", offset).unwrap();
+        if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
+            out.push_str("   | ");
+        } else {
+            write!(out, "{:4} ", chunk.lines[offset]).unwrap();
+        }
+
+        let op = match OpCode::from_byte(chunk.code[offset]) {
+            Some(op) => op,
+            None => {
+                writeln!(out, "Unknown opcode {}", chunk.code[offset]).unwrap();
+                return offset + 1;
+            }
+        };
+        let name = format!("{:?}", op);
+        match op {
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Method => {
+                let constant = chunk.read_u16(offset + 1);
+                writeln!(
+                    out,
+                    "{:<16} {:4} '{}'",
+                    name,
+                    constant,
+                    self.constant(chunk, constant)
+                )
+                .unwrap();
+                offset + 3
+            }
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call => {
+                writeln!(out, "{:<16} {:4}", name, chunk.code[offset + 1]).unwrap();
+                offset + 2
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                let jump = chunk.read_u16(offset + 1) as usize;
+                let target = if op == OpCode::Loop {
+                    offset + 3 - jump
+                } else {
+                    offset + 3 + jump
+                };
+                writeln!(out, "{:<16} {:4} -> {}", name, offset, target).unwrap();
+                offset + 3
+            }
+            OpCode::GetProperty | OpCode::SetProperty => {
+                let constant = chunk.read_u16(offset + 1);
+                let cache = chunk.read_u16(offset + 3);
+                writeln!(
+                    out,
+                    "{:<16} {:4} '{}' cache {}",
+                    name,
+                    constant,
+                    self.constant(chunk, constant),
+                    cache
+                )
+                .unwrap();
+                offset + 5
+            }
+            OpCode::Invoke | OpCode::SuperInvoke => {
+                let constant = chunk.read_u16(offset + 1);
+                let arg_count = chunk.code[offset + 3];
+                write!(
+                    out,
+                    "{:<16} ({} args) {:4} '{}'",
+                    name,
+                    arg_count,
+                    constant,
+                    self.constant(chunk, constant)
+                )
+                .unwrap();
+                if op == OpCode::SuperInvoke {
+                    out.push('\n');
+                    return offset + 4;
+                }
+                writeln!(out, " cache {}", chunk.read_u16(offset + 4)).unwrap();
+                offset + 6
+            }
+            OpCode::Closure => {
+                let constant = chunk.read_u16(offset + 1);
+                writeln!(
+                    out,
+                    "{:<16} {:4} {}",
+                    name,
+                    constant,
+                    self.constant(chunk, constant)
+                )
+                .unwrap();
+                let mut offset = offset + 3;
+                if let Some(function) = chunk.constants[constant as usize].as_obj() {
+                    for _ in 0..self.heap.function(function).upvalue_count {
+                        let kind = if chunk.code[offset] == 1 {
+                            "local"
+                        } else {
+                            "upvalue"
+                        };
+                        writeln!(
+                            out,
+                            "{:04}    |                     {} {}",
+                            offset,
+                            kind,
+                            chunk.code[offset + 1]
+                        )
+                        .unwrap();
+                        offset += 2;
+                    }
+                }
+                offset
+            }
+            OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Pop
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::Return
+            | OpCode::Inherit => {
+                writeln!(out, "{}", name).unwrap();
+                offset + 1
+            }
+        }
+    }
+
+    fn constant(&self, chunk: &Chunk, index: u16) -> String {
+        self.heap.format(chunk.constants[index as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::*;
+    use crate::scanner::*;
+
+    fn disassemble(code: &str) -> String {
+        let mut scanner = Scanner::new(code.to_string());
+        scanner.scan().unwrap();
+        let mut heap = Heap::new();
+        let function = Compiler::new(&mut heap, scanner.tokens).compile().unwrap();
+        Disassembler::new(&heap).disassemble(function)
+    }
+
+    #[test]
+    fn disassemble_script() {
+        let expected = "\
+== <script> ==
+0000    1 Constant            1 '1'
+0003    | DefineGlobal        0 'a'
+0006    2 GetGlobal           2 'a'
+0009    | JumpIfFalse         9 -> 20
+0012    | Pop
+0013    | GetGlobal           3 'a'
+0016    | Print
+0017    | Jump               17 -> 21
+0020    | Pop
+0021    | Nil
+0022    | Return
+";
+        let actual = disassemble("var a = 1;\nif (a) print a;");
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn disassemble_nested_functions() {
+        let expected = "\
+== <script> ==
+0000    1 Closure             1 <fn outer>
+0003    | DefineGlobal        0 'outer'
+0006    | Nil
+0007    | Return
+== <fn outer> ==
+0000    1 Constant            0 '1'
+0003    | Closure             1 <fn inner>
+0006    |                     local 1
+0008    | Nil
+0009    | Return
+== <fn inner> ==
+0000    1 GetUpvalue          0
+0002    | Return
+0003    | Nil
+0004    | Return
+";
+        let actual = disassemble("fun outer() { var x = 1; fun inner() { return x; } }");
+        assert_eq!(expected, actual);
+    }
+}
@@ -1,17 +1,356 @@
+use std::fmt;
+
+use crate::token::Span;
+
+// A long trace, from deep recursion say, lists this many of the innermost and
+// of the outermost calls, and leaves out the ones in between.
+const TRACE_EDGE: usize = 5;
+
+// Everything that can go wrong between reading Lox code and running it. The
+// variants follow the stages, so embedders can tell a script that does not
+// compile from one that failed while running. Errors in the source code point
+// at the text they are about with a span. Bytecode only records the line of
+// each instruction, so runtime errors have just that.
 #[derive(Debug)]
-pub enum ErrorType {
-    IOError(u64, String),
-    RuntimeError(u64, String),
-    ScanError(u64, String),
+pub enum Error {
+    // Text that is not a token.
+    Scan {
+        span: Span,
+        message: String,
+    },
+    // Code that does not follow the grammar, at the token `lexeme`, or at the
+    // end of the code when that is None.
+    Parse {
+        span: Span,
+        lexeme: Option<String>,
+        message: String,
+    },
+    // A name or keyword used where it cannot be, such as `this` outside of a
+    // class, reported like parse errors.
+    Resolve {
+        span: Span,
+        lexeme: Option<String>,
+        message: String,
+    },
+    // The calls in progress follow the message, innermost first. Errors
+    // raised outside of the VM, by natives say, have none, and those of calls
+    // from Rust that never reach Lox code have no line either.
+    Runtime {
+        line: u64,
+        message: String,
+        trace: Vec<TraceFrame>,
+    },
+    // A compiled file that is damaged, or was written by another version.
+    Bytecode {
+        message: String,
+    },
+    // Files that cannot be read.
+    Io {
+        message: String,
+        source: Option<std::io::Error>,
+    },
+}
+
+impl Error {
+    // A runtime error without a place, which the VM gives the line of the
+    // call when a native returns it.
+    pub fn runtime(message: impl Into<String>) -> Self {
+        Error::Runtime {
+            line: 0,
+            message: message.into(),
+            trace: vec![],
+        }
+    }
+
+    // The line the error is on, or 0 when it has none.
+    pub fn line(&self) -> u64 {
+        match self {
+            Error::Scan { span, .. } | Error::Parse { span, .. } | Error::Resolve { span, .. } => {
+                span.line
+            }
+            Error::Runtime { line, .. } => *line,
+            Error::Bytecode { .. } | Error::Io { .. } => 0,
+        }
+    }
+
+    // The code the error is about, for errors found in the source.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Error::Scan { span, .. } | Error::Parse { span, .. } | Error::Resolve { span, .. } => {
+                Some(*span)
+            }
+            Error::Runtime { .. } | Error::Bytecode { .. } | Error::Io { .. } => None,
+        }
+    }
+
+    // A code for the kind of error that stays the same across versions, for
+    // tools that sort errors without parsing their messages.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Scan { .. } => "E0001",
+            Error::Parse { .. } => "E0002",
+            Error::Resolve { .. } => "E0003",
+            Error::Runtime { .. } => "E0004",
+            Error::Bytecode { .. } => "E0005",
+            Error::Io { .. } => "E0006",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Error::Scan { message, .. }
+            | Error::Parse { message, .. }
+            | Error::Resolve { message, .. }
+            | Error::Runtime { message, .. }
+            | Error::Bytecode { message }
+            | Error::Io { message, .. } => message,
+        }
+    }
+
+    // The status to exit with, as in sysexits.h: 65 for code that does not
+    // compile or load, 70 for a failure while running and 74 for input that
+    // cannot be read.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Scan { .. }
+            | Error::Parse { .. }
+            | Error::Resolve { .. }
+            | Error::Bytecode { .. } => 65,
+            Error::Runtime { .. } => 70,
+            Error::Io { .. } => 74,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let location = |lexeme: &Option<String>| match lexeme {
+            Some(lexeme) => format!("at '{}'", lexeme),
+            None => "at end".to_string(),
+        };
+        match self {
+            Error::Scan { span, message } => {
+                write!(f, "[line {}] Scan error: {}", span.line, message)
+            }
+            Error::Parse {
+                span,
+                lexeme,
+                message,
+            } => write!(
+                f,
+                "[line {}] Parse error {}: {}",
+                span.line,
+                location(lexeme),
+                message
+            ),
+            Error::Resolve {
+                span,
+                lexeme,
+                message,
+            } => write!(
+                f,
+                "[line {}] Resolve error {}: {}",
+                span.line,
+                location(lexeme),
+                message
+            ),
+            Error::Runtime {
+                line,
+                message,
+                trace,
+            } => {
+                if *line > 0 {
+                    write!(f, "[line {}] ", line)?;
+                }
+                write!(f, "Runtime error: {}", message)?;
+                if !trace.is_empty() {
+                    write!(f, "\n{}", format_trace(trace))?;
+                }
+                Ok(())
+            }
+            Error::Bytecode { message } => write!(f, "Bytecode error: {}", message),
+            Error::Io { message, .. } => write!(f, "I/O error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io {
+                source: Some(source),
+                ..
+            } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io {
+            message: error.to_string(),
+            source: Some(error),
+        }
+    }
 }
 
+// A call in progress when a runtime error happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    // None at the top level of a script.
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: u64,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "[{}:{}] in ", file, self.line)?,
+            None => write!(f, "[line {}] in ", self.line)?,
+        }
+        match &self.function {
+            Some(function) => write!(f, "{}()", function),
+            None => write!(f, "script"),
+        }
+    }
+}
+
+// One call per line, as in "[line 12] in fib()".
+pub fn format_trace(trace: &[TraceFrame]) -> String {
+    let mut lines: Vec<String> = trace.iter().map(|frame| frame.to_string()).collect();
+    if lines.len() > 2 * TRACE_EDGE {
+        let omitted = lines.len() - 2 * TRACE_EDGE;
+        lines.splice(
+            TRACE_EDGE..lines.len() - TRACE_EDGE,
+            [format!("... {} more calls ...", omitted)],
+        );
+    }
+    lines.join("\n")
+}
+
+// The errors of one attempt at compiling or running code, one per line.
 #[derive(Debug)]
-pub struct Error(pub u64, pub String);
+pub struct Errors(pub Vec<Error>);
 
-impl std::fmt::Display for Error {
+impl std::fmt::Display for Errors {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Error: line {}, message: {}", self.0, self.1)
+        let lines: Vec<String> = self.0.iter().map(|error| error.to_string()).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl std::error::Error for Errors {}
+
+impl Errors {
+    // The status of the first error. Failing without one is a bug, so it
+    // exits as an internal software error.
+    pub fn exit_code(&self) -> i32 {
+        self.0.first().map_or(70, Error::exit_code)
     }
 }
 
-impl std::error::Error for Error {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(function: Option<&str>, file: Option<&str>, line: u64) -> TraceFrame {
+        TraceFrame {
+            function: function.map(str::to_string),
+            file: file.map(str::to_string),
+            line,
+        }
+    }
+
+    #[test]
+    fn describe_errors() {
+        let span = |line, column, length| Span {
+            line,
+            column,
+            length,
+        };
+        let scan = Error::Scan {
+            span: span(2, 5, 6),
+            message: "Unterminated string.".to_string(),
+        };
+        assert_eq!(
+            "[line 2] Scan error: Unterminated string.",
+            scan.to_string()
+        );
+        assert_eq!(65, scan.exit_code());
+        assert_eq!((2, Some(span(2, 5, 6))), (scan.line(), scan.span()));
+
+        let parse = Error::Parse {
+            span: span(1, 12, 0),
+            lexeme: None,
+            message: "Expect ';' after value.".to_string(),
+        };
+        assert_eq!(
+            "[line 1] Parse error at end: Expect ';' after value.",
+            parse.to_string()
+        );
+        let resolve = Error::Resolve {
+            span: span(3, 7, 4),
+            lexeme: Some("this".to_string()),
+            message: "Can't use 'this' outside of a class.".to_string(),
+        };
+        assert_eq!(
+            "[line 3] Resolve error at 'this': Can't use 'this' outside of a class.",
+            resolve.to_string()
+        );
+        assert_eq!(65, resolve.exit_code());
+
+        let runtime = Error::Runtime {
+            line: 12,
+            message: "Operand must be a number.".to_string(),
+            trace: vec![frame(Some("fib"), None, 12), frame(None, None, 20)],
+        };
+        assert_eq!(
+            "[line 12] Runtime error: Operand must be a number.\n[line 12] in fib()\n[line 20] in script",
+            runtime.to_string()
+        );
+        assert_eq!(70, runtime.exit_code());
+        assert_eq!((12, None), (runtime.line(), runtime.span()));
+        assert_eq!("Runtime error: x", Error::runtime("x").to_string());
+
+        let io = Error::from(std::io::Error::new(std::io::ErrorKind::NotFound, "gone"));
+        assert_eq!("I/O error: gone", io.to_string());
+        assert_eq!((0, "gone"), (io.line(), io.message()));
+        assert!(std::error::Error::source(&io).is_some());
+
+        let codes: Vec<&str> = [&scan, &parse, &resolve, &runtime, &io]
+            .iter()
+            .map(|error| error.code())
+            .collect();
+        assert_eq!(vec!["E0001", "E0002", "E0003", "E0004", "E0006"], codes);
+
+        let errors = Errors(vec![parse, runtime]);
+        assert_eq!(65, errors.exit_code());
+        assert_eq!(70, Errors(vec![]).exit_code());
+        assert!(errors.to_string().starts_with(
+            "[line 1] Parse error at end: Expect ';' after value.\n[line 12] Runtime error"
+        ));
+    }
+
+    #[test]
+    fn format_traces() {
+        let trace = vec![frame(Some("fib"), None, 12), frame(None, None, 20)];
+        assert_eq!(
+            "[line 12] in fib()\n[line 20] in script",
+            format_trace(&trace)
+        );
+        assert_eq!(
+            "[fib.lox:3] in fib()",
+            frame(Some("fib"), Some("fib.lox"), 3).to_string()
+        );
+
+        let deep: Vec<TraceFrame> = (0..12).map(|line| frame(Some("f"), None, line)).collect();
+        let formatted = format_trace(&deep);
+        let lines: Vec<&str> = formatted.lines().collect();
+        assert_eq!(11, lines.len());
+        assert_eq!("[line 4] in f()", lines[4]);
+        assert_eq!("... 2 more calls ...", lines[5]);
+        assert_eq!("[line 7] in f()", lines[6]);
+    }
+}
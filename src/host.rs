@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::error::Error;
+use crate::object::*;
+use crate::value::Value;
+use crate::vm::Vm;
+
+// A Rust type exposed to Lox as a class. Calling the class runs the
+// constructor, whose result becomes the Lox object; methods, getters and
+// setters then get that value back. Host data must not hold Lox values, which
+// the collector cannot see there.
+pub struct ClassBuilder<T> {
+    name: String,
+    arity: usize,
+    constructor: NativeClosure,
+    methods: Vec<(String, usize, NativeClosure)>,
+    getters: Vec<(String, NativeClosure)>,
+    setters: Vec<(String, NativeClosure)>,
+    _data: std::marker::PhantomData<T>,
+}
+
+impl<T: 'static> ClassBuilder<T> {
+    pub fn new<F>(name: &str, arity: usize, constructor: F) -> Self
+    where
+        F: Fn(&mut Vm, &[Value]) -> Result<T, Error> + 'static,
+    {
+        // The constructor is called with the class as its receiver.
+        let constructor = move |vm: &mut Vm, args: &[Value]| {
+            let data = constructor(vm, &args[1..])?;
+            let class = args[0].as_obj().unwrap();
+            let object = vm.heap_mut().alloc(Obj::Foreign(Foreign {
+                class,
+                data: Some(Box::new(data)),
+            }));
+            Ok(Value::obj(object))
+        };
+        ClassBuilder {
+            name: name.to_string(),
+            arity,
+            constructor: Rc::new(constructor),
+            methods: vec![],
+            getters: vec![],
+            setters: vec![],
+            _data: std::marker::PhantomData,
+        }
+    }
+
+    pub fn method<F>(mut self, name: &str, arity: usize, method: F) -> Self
+    where
+        F: Fn(&mut Vm, &mut T, &[Value]) -> Result<Value, Error> + 'static,
+    {
+        let class_name = self.name.clone();
+        let method = move |vm: &mut Vm, args: &[Value]| {
+            with_data(vm, args[0], &class_name, |vm, data| {
+                method(vm, data, &args[1..])
+            })
+        };
+        self.methods
+            .push((name.to_string(), arity, Rc::new(method)));
+        self
+    }
+
+    // A property read with `object.name`.
+    pub fn getter<F>(mut self, name: &str, getter: F) -> Self
+    where
+        F: Fn(&mut Vm, &T) -> Result<Value, Error> + 'static,
+    {
+        let class_name = self.name.clone();
+        let getter = move |vm: &mut Vm, args: &[Value]| {
+            with_data(vm, args[0], &class_name, |vm, data: &mut T| {
+                getter(vm, data)
+            })
+        };
+        self.getters.push((name.to_string(), Rc::new(getter)));
+        self
+    }
+
+    // A property assigned with `object.name = value`.
+    pub fn setter<F>(mut self, name: &str, setter: F) -> Self
+    where
+        F: Fn(&mut Vm, &mut T, Value) -> Result<(), Error> + 'static,
+    {
+        let class_name = self.name.clone();
+        let setter = move |vm: &mut Vm, args: &[Value]| {
+            with_data(vm, args[0], &class_name, |vm, data| {
+                setter(vm, data, args[1])
+            })?;
+            Ok(Value::NIL)
+        };
+        self.setters.push((name.to_string(), Rc::new(setter)));
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Allocates the class and its natives.
+    pub(crate) fn build(self, heap: &mut Heap) -> ObjRef {
+        let mut native = |name: &str, arity: usize, function: NativeClosure| {
+            let name = heap.intern(name);
+            let native = heap.alloc(Obj::Native(NativeFunction {
+                name,
+                arity,
+                function,
+            }));
+            (name, native)
+        };
+        let constructor = native(&self.name, self.arity, self.constructor).1;
+        let methods: HashMap<ObjRef, ObjRef> = self
+            .methods
+            .into_iter()
+            .map(|(name, arity, method)| native(&name, arity, method))
+            .collect();
+        let getters = self
+            .getters
+            .into_iter()
+            .map(|(name, getter)| native(&name, 0, getter))
+            .collect();
+        let setters = self
+            .setters
+            .into_iter()
+            .map(|(name, setter)| native(&name, 1, setter))
+            .collect();
+        let name = heap.intern(&self.name);
+        let shape = heap.root_shape();
+        heap.alloc(Obj::Class(Class {
+            name,
+            methods,
+            shape,
+            host: Some(HostClass {
+                constructor,
+                getters,
+                setters,
+            }),
+        }))
+    }
+}
+
+// Runs `f` on the Rust value of `receiver`, which is taken out of the object
+// meanwhile: a method that calls back into Lox cannot reach it a second time.
+fn with_data<T: 'static, R>(
+    vm: &mut Vm,
+    receiver: Value,
+    class_name: &str,
+    f: impl FnOnce(&mut Vm, &mut T) -> Result<R, Error>,
+) -> Result<R, Error> {
+    let error = |msg: String| Err(Error::runtime(msg));
+    let object = match receiver.as_obj() {
+        Some(obj) if matches!(vm.heap().get(obj), Obj::Foreign(_)) => obj,
+        _ => {
+            let msg = format!("Expected a {} but got {}.", class_name, vm.format(receiver));
+            return error(msg);
+        }
+    };
+    let mut data = match vm.heap_mut().foreign_mut(object).data.take() {
+        Some(data) => data,
+        None => return error(format!("The {} is already in use.", class_name)),
+    };
+    let result = match data.downcast_mut::<T>() {
+        Some(value) => f(vm, value),
+        None => error(format!(
+            "Expected a {} but got {}.",
+            class_name,
+            vm.format(receiver)
+        )),
+    };
+    vm.heap_mut().foreign_mut(object).data = Some(data);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::{FromLox, IntoLox};
+    use crate::test_output::SharedOutput;
+    use std::cell::RefCell;
+
+    struct Connection {
+        host: String,
+        sent: Vec<f64>,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            self.log.borrow_mut().push(format!("closed {}", self.host));
+        }
+    }
+
+    fn connection_class(log: Rc<RefCell<Vec<String>>>) -> ClassBuilder<Connection> {
+        ClassBuilder::new("Connection", 1, move |vm, args| {
+            Ok(Connection {
+                host: String::from_lox(args[0], vm)?,
+                sent: vec![],
+                log: Rc::clone(&log),
+            })
+        })
+        .method("send", 1, |vm, connection, args| {
+            connection.sent.push(f64::from_lox(args[0], vm)?);
+            Ok(Value::number(connection.sent.len() as f64))
+        })
+        .method("each", 1, |vm, connection, args| {
+            for value in connection.sent.clone() {
+                vm.call_function(args[0], &[Value::number(value)])?;
+            }
+            Ok(Value::NIL)
+        })
+        .getter("host", |vm, connection| {
+            Ok(connection.host.as_str().into_lox(vm))
+        })
+        .setter("host", |vm, connection, value| {
+            connection.host = String::from_lox(value, vm)?;
+            Ok(())
+        })
+        .getter("sent", |vm, connection| {
+            Ok(connection.sent.clone().into_lox(vm))
+        })
+    }
+
+    fn run(code: &str) -> (Result<(), Vec<Error>>, String, Vec<String>) {
+        let output = SharedOutput::default();
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.define_class(connection_class(Rc::clone(&log)));
+        let result = vm.interpret(code.to_string());
+        drop(vm);
+        let printed = String::from_utf8(output.0.borrow().clone()).unwrap();
+        let log = log.borrow().clone();
+        (result, printed, log)
+    }
+
+    fn error(code: &str) -> String {
+        match run(code).0 {
+            Err(errors) => match &errors[..] {
+                [Error::Runtime { message, .. }] => message.clone(),
+                other => panic!("unexpected errors {:?}", other),
+            },
+            Ok(_) => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn drive_host_objects_from_lox() {
+        let (result, printed, log) = run("var c = Connection(\"x\");
+print c;
+print c.send(1);
+var send = c.send;
+print send(2);
+print c.host;
+c.host = \"y\";
+print c.host;
+print c.sent.rest.first;
+fun show(n) { print n * 10; }
+c.each(show);
+print Connection;");
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(
+            "Connection instance\n1\n2\nx\ny\n2\n10\n20\nConnection\n",
+            printed
+        );
+        assert_eq!(vec!["closed y"], log);
+    }
+
+    #[test]
+    fn collect_unreachable_host_objects() {
+        let output = SharedOutput::default();
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.define_class(connection_class(Rc::clone(&log)));
+        vm.interpret("var kept = Connection(\"kept\"); Connection(\"lost\");".to_string())
+            .unwrap();
+        vm.collect_garbage();
+        assert_eq!(vec!["closed lost"], *log.borrow());
+        vm.interpret("print kept.host;".to_string()).unwrap();
+        assert_eq!(b"kept\n".to_vec(), *output.0.borrow());
+    }
+
+    #[test]
+    fn reject_misuse_of_host_objects() {
+        assert_eq!("Expected 1 arguments but got 0.", error("Connection();"));
+        assert_eq!("Expected a string but got 1.", error("Connection(1);"));
+        assert_eq!(
+            "Undefined property 'missing'.",
+            error("Connection(\"x\").missing;")
+        );
+        assert_eq!(
+            "Undefined property 'missing'.",
+            error("Connection(\"x\").missing();")
+        );
+        assert_eq!(
+            "Property 'port' cannot be set.",
+            error("Connection(\"x\").port = 1;")
+        );
+        assert_eq!(
+            "Superclass cannot be a host class.",
+            error("class Pool < Connection {}")
+        );
+        assert_eq!(
+            "The Connection is already in use.",
+            error("var c = Connection(\"x\"); fun f(n) { c.send(n); } c.send(1); c.each(f);")
+        );
+    }
+}
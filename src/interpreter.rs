@@ -0,0 +1,251 @@
+use std::io::Write;
+
+use crate::convert::{FromLox, IntoLox, IntoLoxArgs};
+use crate::error::Error;
+use crate::host::ClassBuilder;
+use crate::object::NativeFn;
+use crate::value::Value;
+use crate::vm::{InterruptHandle, Options, Vm};
+
+// Runs Lox code inside a Rust program. Globals defined by one call to `eval`
+// stay visible to the next, so a script can be loaded and then driven.
+pub struct Interpreter {
+    vm: Vm,
+}
+
+impl Interpreter {
+    // An interpreter whose scripts print to standard output.
+    pub fn new() -> Self {
+        Interpreter { vm: Vm::new() }
+    }
+
+    // An interpreter whose scripts print to `out`.
+    pub fn with_output(out: Box<dyn Write>) -> Self {
+        Interpreter {
+            vm: Vm::with_output(out),
+        }
+    }
+
+    pub fn with_options(mut self, options: Options) -> Self {
+        self.vm.set_options(options);
+        self
+    }
+
+    // A handle another thread can use to stop the script being evaluated.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.vm.interrupt_handle()
+    }
+
+    pub fn eval(&mut self, code: &str) -> Result<(), Vec<Error>> {
+        self.vm.interpret(code.to_string())
+    }
+
+    // Runs the Lox file at `path`, which stack traces then refer to.
+    pub fn eval_file(&mut self, path: &str) -> Result<(), Vec<Error>> {
+        let code = std::fs::read_to_string(path).map_err(|error| vec![Error::from(error)])?;
+        self.vm.interpret_file(path, code)
+    }
+
+    // Calls the global function `name` with the arguments in a tuple, and
+    // converts what it returns.
+    pub fn call<A: IntoLoxArgs, R: FromLox>(
+        &mut self,
+        name: &str,
+        args: A,
+    ) -> Result<R, Vec<Error>> {
+        let callee = self.global(name)?;
+        let args = args.into_lox_args(&mut self.vm);
+        let result = self
+            .vm
+            .call_function(callee, &args)
+            .map_err(|error| vec![error])?;
+        R::from_lox(result, &self.vm).map_err(|error| vec![error])
+    }
+
+    pub fn get<R: FromLox>(&self, name: &str) -> Result<R, Vec<Error>> {
+        R::from_lox(self.global(name)?, &self.vm).map_err(|error| vec![error])
+    }
+
+    pub fn set<T: IntoLox>(&mut self, name: &str, value: T) {
+        let value = value.into_lox(&mut self.vm);
+        self.vm.set_global(name, value);
+    }
+
+    fn global(&self, name: &str) -> Result<Value, Vec<Error>> {
+        self.vm
+            .get_global(name)
+            .ok_or_else(|| vec![Error::runtime(format!("Undefined variable '{}'.", name))])
+    }
+
+    // Makes a Rust type usable from Lox as the class it describes.
+    pub fn define_class<T: 'static>(&mut self, class: ClassBuilder<T>) {
+        self.vm.define_class(class);
+    }
+
+    // Makes a Rust function callable from Lox as the global `name`.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        self.vm.define_native(name, arity, function);
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::format_trace;
+    use crate::test_output::SharedOutput;
+    use crate::token::Span;
+    use std::collections::HashMap;
+
+    #[test]
+    fn eval_keeps_state_between_calls() {
+        let output = SharedOutput::default();
+        let mut interpreter = Interpreter::with_output(Box::new(output.clone()));
+        interpreter
+            .eval("var greeting = \"hello\"; fun greet(name) { return greeting + \" \" + name; }")
+            .unwrap();
+        interpreter.eval("print greet(\"lox\");").unwrap();
+        assert_eq!(b"hello lox\n".to_vec(), *output.0.borrow());
+
+        match interpreter.eval("print missing;") {
+            Err(errors) => match &errors[..] {
+                [Error::Runtime {
+                    line: 1, message, ..
+                }] => {
+                    assert_eq!("Undefined variable 'missing'.", message)
+                }
+                other => panic!("unexpected errors {:?}", other),
+            },
+            Ok(_) => panic!("expected a runtime error"),
+        }
+        assert!(matches!(
+            interpreter.eval("print (;").unwrap_err()[..],
+            [Error::Parse {
+                span: Span {
+                    line: 1,
+                    column: 8,
+                    length: 1
+                },
+                ..
+            }]
+        ));
+        match &interpreter.eval_file("missing.lox").unwrap_err()[..] {
+            [error @ Error::Io { .. }] => {
+                assert_eq!(74, error.exit_code());
+                assert!(std::error::Error::source(error).is_some());
+            }
+            other => panic!("unexpected errors {:?}", other),
+        }
+    }
+
+    #[test]
+    fn call_lox_functions_with_rust_values() {
+        let mut interpreter = Interpreter::with_output(Box::new(std::io::sink()));
+        interpreter
+            .eval(
+                "fun add(a, b) { return a + b; }
+fun sum(list) {
+  var total = 0;
+  while (list != nil) { total = total + list.first; list = list.rest; }
+  return total;
+}
+fun names(map) { return map.first + \" \" + map.last; }
+class Pair { init(first, rest) { this.first = first; this.rest = rest; } }
+fun pairs() { return Pair(\"a\", Pair(\"b\", nil)); }
+fun fail() { return nil + 1; }",
+            )
+            .unwrap();
+
+        assert_eq!(3.0, interpreter.call::<_, f64>("add", (1.0, 2.0)).unwrap());
+        let greeting: String = interpreter.call("add", ("a", "b".to_string())).unwrap();
+        assert_eq!("ab", greeting);
+        assert_eq!(
+            6.0,
+            interpreter
+                .call::<_, f64>("sum", (vec![1.0, 2.0, 3.0],))
+                .unwrap()
+        );
+        let map = HashMap::from([
+            ("first".to_string(), "Ada"),
+            ("last".to_string(), "Lovelace"),
+        ]);
+        let name: String = interpreter.call("names", (map,)).unwrap();
+        assert_eq!("Ada Lovelace", name);
+        let pairs: Vec<String> = interpreter.call("pairs", ()).unwrap();
+        assert_eq!(vec!["a", "b"], pairs);
+        let instance: HashMap<String, Option<String>> =
+            interpreter.call("Pair", ("x", ())).unwrap();
+        assert_eq!(Some(&None), instance.get("rest"));
+
+        match interpreter.call::<_, ()>("fail", ()) {
+            Err(errors) => match &errors[..] {
+                [Error::Runtime {
+                    line: 10,
+                    message,
+                    trace,
+                }] => {
+                    assert_eq!("Operands must be two numbers or two strings.", message);
+                    // Calls from Rust start a stack of their own.
+                    assert_eq!("[line 10] in fail()", format_trace(trace));
+                }
+                other => panic!("unexpected errors {:?}", other),
+            },
+            Ok(_) => panic!("expected a runtime error"),
+        }
+        assert!(interpreter.call::<_, f64>("add", (1.0,)).is_err());
+        assert!(interpreter.call::<_, f64>("add", (1.0, true)).is_err());
+        assert!(interpreter.call::<_, ()>("missing", ()).is_err());
+        assert!(interpreter.call::<_, bool>("add", (1.0, 2.0)).is_err());
+        // The failed calls leave nothing behind.
+        assert_eq!(5.0, interpreter.call::<_, f64>("add", (2.0, 3.0)).unwrap());
+    }
+
+    #[test]
+    fn get_and_set_globals() {
+        let mut interpreter = Interpreter::with_output(Box::new(std::io::sink()));
+        interpreter.set("limit", 10.0);
+        interpreter.set("tags", vec!["a", "b"]);
+        interpreter
+            .eval("var doubled = limit * 2; var tag = tags.rest.first;")
+            .unwrap();
+        assert_eq!(20.0, interpreter.get::<f64>("doubled").unwrap());
+        assert_eq!("b", interpreter.get::<String>("tag").unwrap());
+        assert!(interpreter.get::<f64>("tag").is_err());
+        assert!(interpreter.get::<f64>("missing").is_err());
+    }
+
+    #[test]
+    fn nans_from_rust_stay_numbers() {
+        let output = SharedOutput::default();
+        let mut interpreter = Interpreter::with_output(Box::new(output.clone()));
+        interpreter.set("w", f64::from_bits(0x7ffc_0000_0000_0001));
+        interpreter.set("v", f64::from_bits(0xfffc_0000_0000_0001));
+        interpreter
+            .eval("print w; print w == nil; print v;")
+            .unwrap();
+        assert_eq!(b"NaN\nfalse\nNaN\n".to_vec(), *output.0.borrow());
+        assert!(interpreter.get::<f64>("w").unwrap().is_nan());
+    }
+
+    #[test]
+    fn natives_call_back_into_lox() {
+        fn twice(vm: &mut Vm, args: &[Value]) -> Result<Value, Error> {
+            let once = vm.call_function(args[0], &[args[1]])?;
+            vm.call_function(args[0], &[once])
+        }
+        let output = SharedOutput::default();
+        let mut interpreter = Interpreter::with_output(Box::new(output.clone()));
+        interpreter.define_native("twice", 2, twice);
+        interpreter
+            .eval("fun inc(n) { return n + 1; } print twice(inc, 1); print twice(inc, nil);")
+            .unwrap_err();
+        assert_eq!(b"3\n".to_vec(), *output.0.borrow());
+        interpreter.eval("print twice(inc, 5);").unwrap();
+        assert_eq!(b"3\n7\n".to_vec(), *output.0.borrow());
+    }
+}
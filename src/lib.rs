@@ -0,0 +1,35 @@
+// The Lox scanner, compiler and virtual machine, for programs that embed Lox.
+// `Interpreter` runs Lox code; the modules give access to each stage.
+
+pub mod ast;
+pub mod ast_printer;
+pub mod bench;
+pub mod bytecode;
+pub mod chunk;
+pub mod compiler;
+pub mod convert;
+pub mod disassembler;
+pub mod error;
+pub mod host;
+mod interpreter;
+mod natives;
+pub mod object;
+pub mod optimizer;
+pub mod parser;
+mod profiler;
+pub mod scanner;
+#[cfg(test)]
+mod test_output;
+pub mod token;
+pub mod value;
+pub mod vm;
+
+pub use convert::{FromLox, IntoLox, IntoLoxArgs};
+pub use error::Error;
+pub use host::ClassBuilder;
+pub use interpreter::Interpreter;
+pub use natives::Capabilities;
+pub use scanner::Scanner;
+pub use token::{Keyword, Span, Token, TokenType};
+pub use value::Value;
+pub use vm::InterruptHandle;
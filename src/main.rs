@@ -1,63 +1,429 @@
 use std::env;
 use std::error::Error;
 use std::process;
+use std::time::Duration;
 
-mod error;
-mod scanner;
-mod token;
+use rlox::ast_printer::AstFormat;
+use rlox::bench;
+use rlox::error::Errors;
+use rlox::optimizer::OptLevel;
+use rlox::token::TokenFormat;
+use rlox::vm::Options;
+use rlox::Capabilities;
 
-mod rlox {
-    use super::error::*;
-    use super::scanner::*;
+mod repl;
 
-    pub fn run(code: String) -> Result<(), Box<dyn std::error::Error>> {
+mod cli {
+    use std::path::{Path, PathBuf};
+
+    use rlox::ast_printer::*;
+    use rlox::bench;
+    use rlox::bytecode;
+    use rlox::compiler::*;
+    use rlox::disassembler::*;
+    use rlox::error::*;
+    use rlox::object::*;
+    use rlox::optimizer::*;
+    use rlox::parser::*;
+    use rlox::scanner::*;
+    use rlox::token::*;
+    use rlox::vm::*;
+
+    use super::repl::*;
+
+    pub fn scan(code: String) -> Result<Vec<Token>, Box<dyn std::error::Error>> {
         let mut scanner = Scanner::new(code);
-        match scanner.scan() {
-            Err(ErrorType::IOError(line, msg)) => Err(Box::new(Error(line, msg))),
-            Err(ErrorType::RuntimeError(line, msg)) => Err(Box::new(Error(line, msg))),
-            Err(ErrorType::ScanError(line, msg)) => Err(Box::new(Error(line, msg))),
-            Ok(_) => {
-                for token in scanner.tokens.iter() {
+        scanner.scan()?;
+        Ok(scanner.tokens)
+    }
+
+    pub fn print_ast(
+        code: String,
+        format: AstFormat,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let tokens = scan(code)?;
+        let printer = AstPrinter::new(format);
+        // A lone expression, as typed at the prompt, is printed without a statement around it.
+        if let Ok(expr) = Parser::new(tokens.clone()).parse_expression() {
+            return Ok(printer.print_expr(&expr));
+        }
+        let statements = Parser::new(tokens).parse().map_err(Errors)?;
+        Ok(printer.print(&statements))
+    }
+
+    pub fn run(vm: &mut Vm, code: String) -> Result<(), Box<dyn std::error::Error>> {
+        vm.interpret(code).map_err(Errors)?;
+        Ok(())
+    }
+
+    pub fn load(
+        vm: &mut Vm,
+        file_name: &str,
+        code: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        vm.interpret_file(file_name, code).map_err(Errors)?;
+        Ok(())
+    }
+
+    // Runs either a source file or one written by `compile_file`.
+    pub fn run_file(
+        file_name: &String,
+        options: Options,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let bytes = std::fs::read(file_name)?;
+        let mut vm = Vm::new();
+        vm.set_options(options);
+        let result = if bytecode::is_compiled(&bytes) {
+            vm.interpret_compiled(&bytes)
+                .map_err(|errors| Errors(errors).into())
+        } else {
+            load(&mut vm, file_name, String::from_utf8(bytes)?)
+        };
+        if let Some(report) = vm.gc_report() {
+            eprintln!("{}", report);
+        }
+        if let (Some(report), Some(stacks)) = (vm.profile_report(), vm.folded_stacks()) {
+            let stacks_name = format!(
+                "{}.folded",
+                file_name.strip_suffix(".lox").unwrap_or(file_name)
+            );
+            std::fs::write(&stacks_name, stacks)?;
+            eprintln!("{}", report);
+            eprintln!("[profile] folded stacks written to {}", stacks_name);
+        }
+        result
+    }
+
+    pub fn compile_file(
+        file_name: &String,
+        output_name: &String,
+        opt_level: OptLevel,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let tokens = scan(std::fs::read_to_string(file_name)?)?;
+        let mut heap = Heap::new();
+        let function = Compiler::new(&mut heap, tokens)
+            .with_opt_level(opt_level)
+            .with_file_name(file_name)
+            .compile()
+            .map_err(Errors)?;
+        std::fs::write(output_name, bytecode::serialize(&heap, function))?;
+        Ok(())
+    }
+
+    pub fn print_tokens_file(
+        file_name: &String,
+        format: TokenFormat,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let tokens = scan(std::fs::read_to_string(file_name)?)?;
+        match format {
+            TokenFormat::Debug => {
+                for token in tokens.iter() {
                     println!("{:?}", token);
                 }
-                Ok(())
             }
+            TokenFormat::Json => println!("{}", to_json(&tokens)),
         }
+        Ok(())
     }
 
-    pub fn run_file(file_name: &String) -> Result<(), Box<dyn std::error::Error>> {
-        let code = std::fs::read_to_string(file_name)?;
-        run(code)
+    pub fn print_ast_file(
+        file_name: &String,
+        format: AstFormat,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let tokens = scan(std::fs::read_to_string(file_name)?)?;
+        let statements = Parser::new(tokens).parse().map_err(Errors)?;
+        println!("{}", AstPrinter::new(format).print(&statements));
+        Ok(())
     }
 
-    pub fn run_prompt() -> Result<(), Box<dyn std::error::Error>> {
-        loop {
-            print!("> ");
-            let mut buffer = String::new();
-            std::io::stdin().read_line(&mut buffer)?;
-            run(buffer)?;
+    pub fn disassemble_file(
+        file_name: &String,
+        opt_level: OptLevel,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let tokens = scan(std::fs::read_to_string(file_name)?)?;
+        let mut heap = Heap::new();
+        let function = Compiler::new(&mut heap, tokens)
+            .with_opt_level(opt_level)
+            .compile()
+            .map_err(Errors)?;
+        print!("{}", Disassembler::new(&heap).disassemble(function));
+        Ok(())
+    }
+
+    // Times every script given, or found in a given directory, both scanned and run.
+    pub fn bench_files(
+        paths: &[String],
+        runs: usize,
+        options: Options,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut files: Vec<PathBuf> = vec![];
+        for path in paths {
+            if Path::new(path).is_dir() {
+                let mut scripts = vec![];
+                for entry in std::fs::read_dir(path)? {
+                    let script = entry?.path();
+                    if script
+                        .extension()
+                        .is_some_and(|extension| extension == "lox")
+                    {
+                        scripts.push(script);
+                    }
+                }
+                scripts.sort();
+                files.extend(scripts);
+            } else {
+                files.push(PathBuf::from(path));
+            }
+        }
+
+        println!("{}", bench::HEADER);
+        for file in files {
+            let code = std::fs::read_to_string(&file)?;
+            let name = file.file_stem().unwrap_or_default().to_string_lossy();
+            println!("{}", bench::scan(&name, &code, runs).map_err(Errors)?);
+            println!(
+                "{}",
+                bench::execute(&name, &code, runs, options).map_err(Errors)?
+            );
         }
+        Ok(())
+    }
+
+    pub fn run_prompt(options: Options) -> Result<(), Box<dyn std::error::Error>> {
+        Repl::new(options).run()
     }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let result: Result<(), Box<dyn Error>>;
-
-    if args.len() > 2 {
-        println!("Usage: rlox [script]");
-        process::exit(1);
-    } else if args.len() == 2 {
-        result = rlox::run_file(&args[1]);
+fn usage() -> ! {
+    println!("Usage: rlox [--ast[=tree|lisp|json]] [--gc-threshold=bytes] [--gc-growth=factor]");
+    println!("            [--gc-stress] [--gc-log] [--profile] [-O0|-O1]");
+    println!(
+        "            [--max-steps=count] [--max-heap=bytes] [--max-depth=calls] [--timeout=ms]"
+    );
+    println!("            [--allow=clock,fs,env,process] [script]");
+    println!("       rlox tokens [--format debug|json] script");
+    println!("       rlox ast [--format tree|lisp|json] script");
+    println!("       rlox disasm [-O0|-O1] script");
+    println!("       rlox compile [-O0|-O1] script [-o output]");
+    println!("       rlox run [--gc-*] [--profile] [--max-*] [--timeout=ms] [--allow=...] script");
+    println!("       rlox bench [--runs=count] [-O0|-O1] [script|directory]...");
+    process::exit(1);
+}
+
+fn tool_args<F>(args: &[String], default: F, parse: fn(&str) -> Option<F>) -> (F, String) {
+    let mut format = default;
+    let mut file_name = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--format" {
+            format = args
+                .next()
+                .and_then(|name| parse(name))
+                .unwrap_or_else(|| usage());
+        } else if let Some(name) = arg.strip_prefix("--format=") {
+            format = parse(name).unwrap_or_else(|| usage());
+        } else if arg.starts_with("--") || file_name.is_some() {
+            usage();
+        } else {
+            file_name = Some(arg.clone());
+        }
+    }
+    (format, file_name.unwrap_or_else(|| usage()))
+}
+
+// Takes the optimization flags out of the arguments, the last one wins.
+fn opt_level_args(args: &[String]) -> (OptLevel, Vec<String>) {
+    let mut opt_level = OptLevel::default();
+    let mut rest = vec![];
+    for arg in args {
+        match OptLevel::parse(arg) {
+            Some(level) => opt_level = level,
+            None => rest.push(arg.clone()),
+        }
+    }
+    (opt_level, rest)
+}
+
+// The benchmark suite in benches/ runs when no scripts are given.
+fn bench_args(args: &[String]) -> (usize, Options, Vec<String>) {
+    let (opt_level, args) = opt_level_args(args);
+    let options = Options {
+        opt_level,
+        ..Options::default()
+    };
+    let mut runs = bench::DEFAULT_RUNS;
+    let mut paths = vec![];
+    for arg in args {
+        if let Some(count) = arg.strip_prefix("--runs=") {
+            runs = count.parse().unwrap_or_else(|_| usage());
+        } else if arg.starts_with('-') {
+            usage();
+        } else {
+            paths.push(arg);
+        }
+    }
+    if paths.is_empty() {
+        paths.push("benches".to_string());
+    }
+    (runs, options, paths)
+}
+
+// foo.lox compiles to foo.loxc unless an output file is given.
+fn compiled_name(file_name: &str) -> String {
+    let stem = file_name.strip_suffix(".lox").unwrap_or(file_name);
+    format!("{}.loxc", stem)
+}
+
+// Scripts run from the command line are trusted with the whole host.
+fn default_options() -> Options {
+    Options {
+        capabilities: Capabilities::all(),
+        ..Options::default()
+    }
+}
+
+// Applies a flag that sets up the VM, returning whether it was one.
+fn options_arg(arg: &str, options: &mut Options) -> bool {
+    if arg == "--gc-stress" {
+        options.gc.stress = true;
+    } else if arg == "--gc-log" {
+        options.gc.log = true;
+    } else if arg == "--profile" {
+        options.profile = true;
+    } else if let Some(bytes) = arg.strip_prefix("--gc-threshold=") {
+        options.gc.initial_threshold = bytes.parse().unwrap_or_else(|_| usage());
+    } else if let Some(factor) = arg.strip_prefix("--gc-growth=") {
+        options.gc.growth_factor = factor.parse().unwrap_or_else(|_| usage());
+    } else if let Some(count) = arg.strip_prefix("--max-steps=") {
+        options.limits.steps = Some(count.parse().unwrap_or_else(|_| usage()));
+    } else if let Some(bytes) = arg.strip_prefix("--max-heap=") {
+        options.limits.heap_bytes = Some(bytes.parse().unwrap_or_else(|_| usage()));
+    } else if let Some(calls) = arg.strip_prefix("--max-depth=") {
+        options.limits.call_depth = Some(calls.parse().unwrap_or_else(|_| usage()));
+    } else if let Some(ms) = arg.strip_prefix("--timeout=") {
+        let ms = ms.parse().unwrap_or_else(|_| usage());
+        options.limits.time = Some(Duration::from_millis(ms));
+    } else if let Some(modules) = arg.strip_prefix("--allow=") {
+        options.capabilities = Capabilities::parse(modules).unwrap_or_else(|| usage());
+    } else if let Some(level) = OptLevel::parse(arg) {
+        options.opt_level = level;
     } else {
-        result = rlox::run_prompt();
+        return false;
     }
+    true
+}
+
+fn run_args(args: &[String]) -> (Options, String) {
+    let mut options = default_options();
+    let mut file_name = None;
+    for arg in args {
+        if options_arg(arg, &mut options) {
+            continue;
+        } else if arg.starts_with('-') || file_name.is_some() {
+            usage();
+        } else {
+            file_name = Some(arg.clone());
+        }
+    }
+    (options, file_name.unwrap_or_else(|| usage()))
+}
+
+fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut files: Vec<&String> = vec![];
+    let mut ast_format: Option<AstFormat> = None;
+    let mut options = default_options();
+    for arg in args {
+        if arg == "--ast" {
+            ast_format = Some(AstFormat::Tree);
+        } else if let Some(name) = arg.strip_prefix("--ast=") {
+            ast_format = Some(AstFormat::parse(name).unwrap_or_else(|| usage()));
+        } else if options_arg(arg, &mut options) {
+            continue;
+        } else if arg.starts_with('-') {
+            usage();
+        } else {
+            files.push(arg);
+        }
+    }
+
+    if files.len() > 1 {
+        usage();
+    } else if let Some(format) = ast_format {
+        match files.first() {
+            Some(file_name) => cli::print_ast_file(file_name, format),
+            None => usage(),
+        }
+    } else if let Some(file_name) = files.first() {
+        cli::run_file(file_name, options)
+    } else {
+        cli::run_prompt(options)
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    // Output of the tools is read by other programs, so no status line follows it.
+    let mut report_status = false;
+    let result = match args.first().map(|arg| arg.as_str()) {
+        Some("tokens") => {
+            let (format, file_name) = tool_args(&args[1..], TokenFormat::Debug, TokenFormat::parse);
+            cli::print_tokens_file(&file_name, format)
+        }
+        Some("ast") => {
+            let (format, file_name) = tool_args(&args[1..], AstFormat::Tree, AstFormat::parse);
+            cli::print_ast_file(&file_name, format)
+        }
+        Some("disasm") => {
+            let (opt_level, args) = opt_level_args(&args[1..]);
+            match args.as_slice() {
+                [file_name] => cli::disassemble_file(file_name, opt_level),
+                _ => usage(),
+            }
+        }
+        Some("compile") => {
+            let (opt_level, args) = opt_level_args(&args[1..]);
+            match args.as_slice() {
+                [file_name] => cli::compile_file(file_name, &compiled_name(file_name), opt_level),
+                [file_name, flag, output_name] if flag == "-o" => {
+                    cli::compile_file(file_name, output_name, opt_level)
+                }
+                _ => usage(),
+            }
+        }
+        Some("bench") => {
+            let (runs, options, paths) = bench_args(&args[1..]);
+            cli::bench_files(&paths, runs, options)
+        }
+        Some("run") => {
+            let (options, file_name) = run_args(&args[1..]);
+            report_status = true;
+            cli::run_file(&file_name, options)
+        }
+        _ => {
+            report_status = true;
+            run(&args)
+        }
+    };
 
     match result {
-        Ok(_) => println!("rlox exited successfully."),
+        Ok(_) => {
+            if report_status {
+                println!("rlox exited successfully.");
+            }
+        }
         Err(error) => {
             println!("rlox exited with an error: {}", error);
-            process::exit(1);
+            // Errors of Lox code exit with the status of their kind.
+            let code = if let Some(errors) = error.downcast_ref::<Errors>() {
+                errors.exit_code()
+            } else if let Some(error) = error.downcast_ref::<rlox::Error>() {
+                error.exit_code()
+            } else if error.is::<std::io::Error>() {
+                74
+            } else {
+                1
+            };
+            process::exit(code);
         }
     }
     process::exit(0);
@@ -0,0 +1,250 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::convert::{FromLox, IntoLox};
+use crate::error::Error;
+use crate::object::NativeFn;
+use crate::value::Value;
+use crate::vm::Vm;
+
+// The built-in functions of a module, by name and arity.
+type Module = &'static [(&'static str, usize, NativeFn)];
+
+// The groups of built-in functions a script can see. Scripts only get what
+// they are given, and embedders that run untrusted code should grant no more
+// than the default, which keeps the host's files, environment and process out
+// of reach.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    // clock()
+    pub clock: bool,
+    // readFile(path) and writeFile(path, text)
+    pub fs: bool,
+    // getEnv(name)
+    pub env: bool,
+    // exit(code)
+    pub process: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            clock: true,
+            ..Capabilities::none()
+        }
+    }
+}
+
+impl Capabilities {
+    pub fn none() -> Self {
+        Capabilities {
+            clock: false,
+            fs: false,
+            env: false,
+            process: false,
+        }
+    }
+
+    pub fn all() -> Self {
+        Capabilities {
+            clock: true,
+            fs: true,
+            env: true,
+            process: true,
+        }
+    }
+
+    // Parses a comma-separated list of the modules to allow, such as "clock,fs".
+    pub fn parse(list: &str) -> Option<Self> {
+        let mut capabilities = Capabilities::none();
+        for module in list.split(',').filter(|module| !module.is_empty()) {
+            match module {
+                "clock" => capabilities.clock = true,
+                "fs" => capabilities.fs = true,
+                "env" => capabilities.env = true,
+                "process" => capabilities.process = true,
+                _ => return None,
+            }
+        }
+        Some(capabilities)
+    }
+
+    fn modules(&self) -> [(bool, Module); 4] {
+        [
+            (self.clock, &[("clock", 0, clock)]),
+            (
+                self.fs,
+                &[("readFile", 1, read_file), ("writeFile", 2, write_file)],
+            ),
+            (self.env, &[("getEnv", 1, get_env)]),
+            (self.process, &[("exit", 1, exit)]),
+        ]
+    }
+}
+
+// Defines the built-in functions of the allowed modules, and removes those of
+// the others. Globals the script or the embedder defined under the same names
+// are left alone.
+pub fn define(vm: &mut Vm, capabilities: Capabilities) {
+    for (allowed, natives) in capabilities.modules() {
+        for (name, arity, function) in natives {
+            let installed = vm.module_natives_mut().remove(name);
+            let ours = match vm.get_global(name) {
+                Some(value) => installed.is_some() && value.as_obj() == installed,
+                None => true,
+            };
+            if !ours {
+                continue;
+            }
+            if allowed {
+                vm.define_native(name, *arity, *function);
+                if let Some(native) = vm.get_global(name).and_then(|value| value.as_obj()) {
+                    vm.module_natives_mut().insert(name, native);
+                }
+            } else {
+                vm.remove_global(name);
+            }
+        }
+    }
+}
+
+// Seconds since the Unix epoch, for timing scripts.
+fn clock(_vm: &mut Vm, _args: &[Value]) -> Result<Value, Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| Error::runtime(e.to_string()))?;
+    Ok(Value::number(now.as_secs_f64()))
+}
+
+fn read_file(vm: &mut Vm, args: &[Value]) -> Result<Value, Error> {
+    let path = String::from_lox(args[0], vm)?;
+    match std::fs::read_to_string(&path) {
+        Ok(text) => Ok(text.into_lox(vm)),
+        Err(e) => Err(Error::runtime(format!("Could not read {}: {}.", path, e))),
+    }
+}
+
+fn write_file(vm: &mut Vm, args: &[Value]) -> Result<Value, Error> {
+    let path = String::from_lox(args[0], vm)?;
+    let text = String::from_lox(args[1], vm)?;
+    match std::fs::write(&path, text) {
+        Ok(()) => Ok(Value::NIL),
+        Err(e) => Err(Error::runtime(format!("Could not write {}: {}.", path, e))),
+    }
+}
+
+// The value of an environment variable, or nil when it is not set.
+fn get_env(vm: &mut Vm, args: &[Value]) -> Result<Value, Error> {
+    let name = String::from_lox(args[0], vm)?;
+    Ok(std::env::var(name).ok().into_lox(vm))
+}
+
+// Ends the host process with the given status code.
+fn exit(vm: &mut Vm, args: &[Value]) -> Result<Value, Error> {
+    let code = f64::from_lox(args[0], vm)?;
+    vm.flush();
+    std::process::exit(code as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Options;
+
+    fn vm_with(capabilities: Capabilities) -> Vm {
+        let mut vm = Vm::with_output(Box::new(std::io::sink()));
+        vm.set_options(Options {
+            capabilities,
+            ..Options::default()
+        });
+        vm
+    }
+
+    fn error_message(vm: &mut Vm, code: &str) -> String {
+        match vm.interpret(code.to_string()) {
+            Err(errors) => match &errors[..] {
+                [Error::Runtime { message, .. }] => message.clone(),
+                other => panic!("expected a runtime error, found {:?}", other),
+            },
+            Ok(_) => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn parse_capabilities() {
+        assert_eq!(Some(Capabilities::none()), Capabilities::parse(""));
+        assert_eq!(Some(Capabilities::default()), Capabilities::parse("clock"));
+        assert_eq!(
+            Some(Capabilities::all()),
+            Capabilities::parse("process,env,fs,clock")
+        );
+        assert_eq!(None, Capabilities::parse("clock,network"));
+    }
+
+    #[test]
+    fn hide_the_host_by_default() {
+        let mut vm = vm_with(Capabilities::default());
+        assert!(vm.get_global("clock").is_some());
+        for name in ["readFile", "writeFile", "getEnv", "exit"] {
+            let msg = error_message(&mut vm, &format!("{}(\"x\");", name));
+            assert_eq!(format!("Undefined variable '{}'.", name), msg);
+        }
+
+        let vm = vm_with(Capabilities::none());
+        assert!(vm.get_global("clock").is_none());
+    }
+
+    #[test]
+    fn withdraw_capabilities_but_keep_script_globals() {
+        let mut vm = vm_with(Capabilities::all());
+        vm.interpret("fun getEnv(name) { return name; }".to_string())
+            .unwrap();
+        vm.set_options(Options {
+            capabilities: Capabilities::none(),
+            ..Options::default()
+        });
+        assert!(vm.get_global("readFile").is_none());
+        assert!(vm.get_global("getEnv").is_some());
+    }
+
+    #[test]
+    fn keep_embedder_natives_with_the_same_names() {
+        fn fixed_clock(_vm: &mut Vm, _args: &[Value]) -> Result<Value, Error> {
+            Ok(Value::number(42.0))
+        }
+        let mut vm = vm_with(Capabilities::all());
+        vm.define_native("clock", 0, fixed_clock);
+        for capabilities in [Capabilities::none(), Capabilities::all()] {
+            vm.set_options(Options {
+                capabilities,
+                ..Options::default()
+            });
+            vm.interpret("var now = clock();".to_string()).unwrap();
+            assert_eq!("42", vm.format(vm.get_global("now").unwrap()));
+        }
+        assert!(vm.get_global("exit").is_some());
+    }
+
+    #[test]
+    fn use_granted_modules() {
+        let mut vm = vm_with(Capabilities::all());
+        let path = std::env::temp_dir().join(format!("rlox-natives-{}.txt", std::process::id()));
+        let path_value = path.to_str().unwrap().into_lox(&mut vm);
+        vm.set_global("path", path_value);
+        vm.interpret("writeFile(path, \"saved\"); var text = readFile(path);".to_string())
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let text = vm.get_global("text").unwrap();
+        assert_eq!("saved", String::from_lox(text, &vm).unwrap());
+        assert!(error_message(&mut vm, "readFile(path);").starts_with("Could not read "));
+
+        std::env::set_var("RLOX_NATIVES_TEST", "set");
+        vm.interpret(
+            "var set = getEnv(\"RLOX_NATIVES_TEST\"); var unset = getEnv(\"RLOX_NATIVES_UNSET\");"
+                .to_string(),
+        )
+        .unwrap();
+        let value = |name| vm.format(vm.get_global(name).unwrap());
+        assert_eq!("set", value("set"));
+        assert_eq!("nil", value("unset"));
+    }
+}
@@ -0,0 +1,760 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::chunk::{Chunk, InlineCache};
+use crate::error::Error;
+use crate::value::Value;
+use crate::vm::Vm;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct ObjRef(usize);
+
+#[cfg(feature = "nan-boxing")]
+impl ObjRef {
+    pub fn index(self) -> usize {
+        self.0
+    }
+
+    pub fn from_index(index: usize) -> Self {
+        ObjRef(index)
+    }
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Rc<Chunk>,
+    pub name: Option<ObjRef>,
+}
+
+// A function implemented in Rust, called with the arguments of the call. A
+// runtime error it returns is reported at the line of the call.
+pub type NativeFn = fn(&mut Vm, &[Value]) -> Result<Value, Error>;
+
+// Natives of host classes capture the Rust closures they wrap.
+pub type NativeClosure = Rc<dyn Fn(&mut Vm, &[Value]) -> Result<Value, Error>>;
+
+// Called as a method, a native gets the receiver before the arguments.
+pub struct NativeFunction {
+    pub name: ObjRef,
+    pub arity: usize,
+    pub function: NativeClosure,
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NativeFunction")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+#[derive(Debug)]
+pub enum Upvalue {
+    // Index of the captured variable's stack slot while it is still on the stack.
+    Open(usize),
+    Closed(Value),
+}
+
+// A shape is a heap object, kept alive by its class, the instances using it
+// and the inline caches that mention it.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct ShapeId(ObjRef);
+
+// The layout shared by instances that were given the same fields in the same
+// order: each field name maps to a slot in the instance's field vector.
+#[derive(Debug, Default)]
+pub struct Shape {
+    slots: HashMap<ObjRef, usize>,
+    names: Vec<ObjRef>,
+    // The shapes reached by adding one more field.
+    transitions: HashMap<ObjRef, ShapeId>,
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: ObjRef,
+    pub methods: HashMap<ObjRef, ObjRef>,
+    // Shape of new instances. Every class has its own, so a shape also identifies the class.
+    pub shape: ShapeId,
+    pub host: Option<HostClass>,
+}
+
+// What a class implemented in Rust has besides its methods, all of them natives.
+// Its instances are `Foreign` objects made by the constructor.
+#[derive(Debug)]
+pub struct HostClass {
+    pub constructor: ObjRef,
+    pub getters: HashMap<ObjRef, ObjRef>,
+    pub setters: HashMap<ObjRef, ObjRef>,
+}
+
+// An instance of a class implemented in Rust. The Rust value is taken out
+// while one of its methods runs, so it is None during such a call.
+#[derive(Debug)]
+pub struct Foreign {
+    pub class: ObjRef,
+    pub data: Option<Box<dyn Any>>,
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: ObjRef,
+    pub shape: ShapeId,
+    pub fields: Vec<Value>,
+}
+
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+#[derive(Debug)]
+pub enum Obj {
+    String(String),
+    Function(Function),
+    Native(NativeFunction),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    Foreign(Foreign),
+    BoundMethod(BoundMethod),
+    Shape(Shape),
+}
+
+impl Obj {
+    // Approximate number of bytes the object occupies, used to pace collections.
+    fn size(&self) -> usize {
+        let extra = match self {
+            Obj::String(string) => string.len(),
+            Obj::Function(function) => {
+                function.chunk.code.len()
+                    + function.chunk.lines.len() * std::mem::size_of::<u64>()
+                    + function.chunk.constants.len() * std::mem::size_of::<Value>()
+            }
+            Obj::Closure(closure) => closure.upvalues.len() * std::mem::size_of::<ObjRef>(),
+            Obj::Class(class) => class.methods.len() * std::mem::size_of::<(ObjRef, ObjRef)>(),
+            Obj::Instance(instance) => instance.fields.len() * std::mem::size_of::<Value>(),
+            Obj::Foreign(foreign) => foreign
+                .data
+                .as_ref()
+                .map_or(0, |data| std::mem::size_of_val(&**data)),
+            Obj::Shape(shape) => {
+                shape.slots.len() * std::mem::size_of::<(ObjRef, usize)>()
+                    + shape.names.len() * std::mem::size_of::<ObjRef>()
+                    + shape.transitions.len() * std::mem::size_of::<(ObjRef, ShapeId)>()
+            }
+            Obj::Native(_) | Obj::Upvalue(_) | Obj::BoundMethod(_) => 0,
+        };
+        std::mem::size_of::<Obj>() + extra
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct GcConfig {
+    // Bytes allocated before the first collection.
+    pub initial_threshold: usize,
+    // After a collection, the next one happens once the surviving bytes have grown this many times.
+    pub growth_factor: usize,
+    // Collect after every allocation, to flush out values the VM forgot to root.
+    pub stress: bool,
+    // Report every collection on stderr.
+    pub log: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            initial_threshold: 1024 * 1024,
+            growth_factor: 2,
+            stress: false,
+            log: false,
+        }
+    }
+}
+
+// What a single collection did.
+#[derive(Debug, Clone, Copy)]
+pub struct Collection {
+    pub objects_freed: usize,
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+    pub next_gc: usize,
+    pub pause: Duration,
+}
+
+// Totals over the lifetime of a heap.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GcStats {
+    pub collections: usize,
+    pub bytes_allocated: usize,
+    pub bytes_freed: usize,
+    pub objects_freed: usize,
+    pub total_pause: Duration,
+    pub longest_pause: Duration,
+}
+
+pub struct Heap {
+    // Freed slots hold None until an allocation reuses them.
+    objects: Vec<Option<Obj>>,
+    marks: Vec<bool>,
+    free: Vec<usize>,
+    // The intern table holds its strings weakly: unmarked ones are dropped on collection.
+    strings: HashMap<String, ObjRef>,
+    gray: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    // Set by every allocation and cleared by collections, for stress mode.
+    allocated_since_gc: bool,
+    config: GcConfig,
+    stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::with_config(GcConfig::default())
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap::default()
+    }
+
+    pub fn with_config(config: GcConfig) -> Self {
+        Heap {
+            objects: vec![],
+            marks: vec![],
+            free: vec![],
+            strings: HashMap::new(),
+            gray: vec![],
+            bytes_allocated: 0,
+            next_gc: config.initial_threshold,
+            allocated_since_gc: false,
+            config,
+            stats: GcStats::default(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: GcConfig) {
+        self.config = config;
+        self.next_gc = config.initial_threshold;
+    }
+
+    pub fn config(&self) -> GcConfig {
+        self.config
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = obj.size();
+        self.bytes_allocated += size;
+        self.stats.bytes_allocated += size;
+        self.allocated_since_gc = true;
+        match self.free.pop() {
+            Some(index) => {
+                self.objects[index] = Some(obj);
+                ObjRef(index)
+            }
+            None => {
+                self.objects.push(Some(obj));
+                self.marks.push(false);
+                ObjRef(self.objects.len() - 1)
+            }
+        }
+    }
+
+    // Strings are interned, so equal strings always share one object.
+    pub fn intern(&mut self, string: &str) -> ObjRef {
+        if let Some(obj) = self.strings.get(string) {
+            return *obj;
+        }
+        let obj = self.alloc(Obj::String(string.to_string()));
+        self.strings.insert(string.to_string(), obj);
+        obj
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Obj {
+        match &self.objects[obj.0] {
+            Some(obj) => obj,
+            None => unreachable!("use of collected object {}", obj.0),
+        }
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Obj {
+        match &mut self.objects[obj.0] {
+            Some(obj) => obj,
+            None => unreachable!("use of collected object {}", obj.0),
+        }
+    }
+
+    pub fn should_collect(&self) -> bool {
+        (self.config.stress && self.allocated_since_gc) || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    // Frees every object not reachable from `roots`.
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = ObjRef>) -> Collection {
+        let start = Instant::now();
+        let bytes_before = self.bytes_allocated;
+        for root in roots {
+            self.mark(root);
+        }
+        while let Some(obj) = self.gray.pop() {
+            self.blacken(obj);
+        }
+
+        let marks = &self.marks;
+        self.strings.retain(|_, obj| marks[obj.0]);
+
+        let mut freed = 0;
+        self.bytes_allocated = 0;
+        for (index, slot) in self.objects.iter_mut().enumerate() {
+            if self.marks[index] {
+                self.marks[index] = false;
+                self.bytes_allocated += slot.as_ref().map_or(0, Obj::size);
+            } else if slot.take().is_some() {
+                self.free.push(index);
+                freed += 1;
+            }
+        }
+        self.next_gc = std::cmp::max(
+            self.bytes_allocated * self.config.growth_factor,
+            self.config.initial_threshold,
+        );
+        self.allocated_since_gc = false;
+
+        let collection = Collection {
+            objects_freed: freed,
+            bytes_before,
+            bytes_after: self.bytes_allocated,
+            next_gc: self.next_gc,
+            pause: start.elapsed(),
+        };
+        self.stats.collections += 1;
+        self.stats.bytes_freed += bytes_before.saturating_sub(self.bytes_allocated);
+        self.stats.objects_freed += freed;
+        self.stats.total_pause += collection.pause;
+        self.stats.longest_pause = self.stats.longest_pause.max(collection.pause);
+        collection
+    }
+
+    fn mark(&mut self, obj: ObjRef) {
+        if !self.marks[obj.0] {
+            self.marks[obj.0] = true;
+            self.gray.push(obj);
+        }
+    }
+
+    fn mark_value(&mut self, value: Value) {
+        if let Some(obj) = value.as_obj() {
+            self.mark(obj);
+        }
+    }
+
+    fn blacken(&mut self, obj: ObjRef) {
+        let mut children: Vec<Value> = vec![];
+        match self.get(obj) {
+            Obj::String(_) => {}
+            Obj::Function(function) => {
+                children.extend(function.name.map(Value::obj));
+                children.extend(function.chunk.constants.iter().copied());
+                // A cache must not outlive what it names, or a new object
+                // reusing the slot could match it.
+                for cache in function.chunk.caches.iter() {
+                    match cache.get() {
+                        InlineCache::Empty => {}
+                        InlineCache::Field { shape, .. } => children.push(Value::obj(shape.0)),
+                        InlineCache::Method { shape, method } => {
+                            children.push(Value::obj(shape.0));
+                            children.push(Value::obj(method));
+                        }
+                        InlineCache::Transition { from, to } => {
+                            children.push(Value::obj(from.0));
+                            children.push(Value::obj(to.0));
+                        }
+                    }
+                }
+            }
+            Obj::Native(native) => children.push(Value::obj(native.name)),
+            Obj::Closure(closure) => {
+                children.push(Value::obj(closure.function));
+                children.extend(closure.upvalues.iter().map(|obj| Value::obj(*obj)));
+            }
+            Obj::Upvalue(Upvalue::Open(_)) => {}
+            Obj::Upvalue(Upvalue::Closed(value)) => children.push(*value),
+            Obj::Class(class) => {
+                children.push(Value::obj(class.name));
+                children.push(Value::obj(class.shape.0));
+                for (name, method) in class.methods.iter() {
+                    children.push(Value::obj(*name));
+                    children.push(Value::obj(*method));
+                }
+                if let Some(host) = &class.host {
+                    children.push(Value::obj(host.constructor));
+                    for (name, accessor) in host.getters.iter().chain(host.setters.iter()) {
+                        children.push(Value::obj(*name));
+                        children.push(Value::obj(*accessor));
+                    }
+                }
+            }
+            Obj::Instance(instance) => {
+                children.push(Value::obj(instance.class));
+                children.push(Value::obj(instance.shape.0));
+                children.extend(instance.fields.iter().copied());
+            }
+            Obj::Foreign(foreign) => children.push(Value::obj(foreign.class)),
+            Obj::BoundMethod(bound) => {
+                children.push(bound.receiver);
+                children.push(Value::obj(bound.method));
+            }
+            // A shape's names are its parent's plus its own last one, so
+            // marking the last one keeps them all. The shapes it leads to live
+            // as long as it does, so instances moving to them find them again.
+            Obj::Shape(shape) => {
+                children.extend(shape.names.last().map(|name| Value::obj(*name)));
+                children.extend(shape.transitions.values().map(|next| Value::obj(next.0)));
+            }
+        }
+        for child in children {
+            self.mark_value(child);
+        }
+    }
+
+    // The interned string equal to `string`, if there is one.
+    pub fn find_string(&self, string: &str) -> Option<ObjRef> {
+        self.strings.get(string).copied()
+    }
+
+    pub fn string(&self, obj: ObjRef) -> &str {
+        match self.get(obj) {
+            Obj::String(string) => string,
+            other => unreachable!("expected a string, found {:?}", other),
+        }
+    }
+
+    pub fn function(&self, obj: ObjRef) -> &Function {
+        match self.get(obj) {
+            Obj::Function(function) => function,
+            other => unreachable!("expected a function, found {:?}", other),
+        }
+    }
+
+    pub fn native(&self, obj: ObjRef) -> &NativeFunction {
+        match self.get(obj) {
+            Obj::Native(native) => native,
+            other => unreachable!("expected a native function, found {:?}", other),
+        }
+    }
+
+    pub fn closure(&self, obj: ObjRef) -> &Closure {
+        match self.get(obj) {
+            Obj::Closure(closure) => closure,
+            other => unreachable!("expected a closure, found {:?}", other),
+        }
+    }
+
+    pub fn class(&self, obj: ObjRef) -> &Class {
+        match self.get(obj) {
+            Obj::Class(class) => class,
+            other => unreachable!("expected a class, found {:?}", other),
+        }
+    }
+
+    pub fn class_mut(&mut self, obj: ObjRef) -> &mut Class {
+        match self.get_mut(obj) {
+            Obj::Class(class) => class,
+            other => unreachable!("expected a class, found {:?}", other),
+        }
+    }
+
+    pub fn instance(&self, obj: ObjRef) -> &Instance {
+        match self.get(obj) {
+            Obj::Instance(instance) => instance,
+            other => unreachable!("expected an instance, found {:?}", other),
+        }
+    }
+
+    pub fn foreign(&self, obj: ObjRef) -> &Foreign {
+        match self.get(obj) {
+            Obj::Foreign(foreign) => foreign,
+            other => unreachable!("expected a foreign object, found {:?}", other),
+        }
+    }
+
+    pub fn foreign_mut(&mut self, obj: ObjRef) -> &mut Foreign {
+        match self.get_mut(obj) {
+            Obj::Foreign(foreign) => foreign,
+            other => unreachable!("expected a foreign object, found {:?}", other),
+        }
+    }
+
+    pub fn instance_mut(&mut self, obj: ObjRef) -> &mut Instance {
+        match self.get_mut(obj) {
+            Obj::Instance(instance) => instance,
+            other => unreachable!("expected an instance, found {:?}", other),
+        }
+    }
+
+    fn shape(&self, shape: ShapeId) -> &Shape {
+        match self.get(shape.0) {
+            Obj::Shape(shape) => shape,
+            other => unreachable!("expected a shape, found {:?}", other),
+        }
+    }
+
+    fn shape_mut(&mut self, shape: ShapeId) -> &mut Shape {
+        match self.get_mut(shape.0) {
+            Obj::Shape(shape) => shape,
+            other => unreachable!("expected a shape, found {:?}", other),
+        }
+    }
+
+    // An empty shape, for the instances of a new class.
+    pub fn root_shape(&mut self) -> ShapeId {
+        ShapeId(self.alloc(Obj::Shape(Shape::default())))
+    }
+
+    pub fn shape_slot(&self, shape: ShapeId, name: ObjRef) -> Option<usize> {
+        self.shape(shape).slots.get(&name).copied()
+    }
+
+    pub fn shape_names(&self, shape: ShapeId) -> &[ObjRef] {
+        &self.shape(shape).names
+    }
+
+    // The shape with `name` added after the fields of `shape`, which is shared
+    // by every instance that gets its fields in the same order.
+    pub fn shape_transition(&mut self, shape: ShapeId, name: ObjRef) -> ShapeId {
+        if let Some(next) = self.shape(shape).transitions.get(&name) {
+            return *next;
+        }
+        let mut slots = self.shape(shape).slots.clone();
+        let mut names = self.shape(shape).names.clone();
+        slots.insert(name, names.len());
+        names.push(name);
+        let next = ShapeId(self.alloc(Obj::Shape(Shape {
+            slots,
+            names,
+            transitions: HashMap::new(),
+        })));
+        self.shape_mut(shape).transitions.insert(name, next);
+        next
+    }
+
+    pub fn upvalue_mut(&mut self, obj: ObjRef) -> &mut Upvalue {
+        match self.get_mut(obj) {
+            Obj::Upvalue(upvalue) => upvalue,
+            other => unreachable!("expected an upvalue, found {:?}", other),
+        }
+    }
+
+    fn format_function(&self, function: ObjRef) -> String {
+        match self.function(function).name {
+            Some(name) => format!("<fn {}>", self.string(name)),
+            None => "<script>".to_string(),
+        }
+    }
+
+    pub fn format(&self, value: Value) -> String {
+        if let Some(value) = value.as_bool() {
+            return value.to_string();
+        }
+        if let Some(value) = value.as_number() {
+            return value.to_string();
+        }
+        let obj = match value.as_obj() {
+            Some(obj) => obj,
+            None => return "nil".to_string(),
+        };
+        match self.get(obj) {
+            Obj::String(string) => string.clone(),
+            Obj::Function(_) => self.format_function(obj),
+            Obj::Native(_) => "<native fn>".to_string(),
+            Obj::Closure(closure) => self.format_function(closure.function),
+            Obj::Upvalue(_) => "upvalue".to_string(),
+            Obj::Class(class) => self.string(class.name).to_string(),
+            Obj::Instance(instance) => {
+                format!("{} instance", self.string(self.class(instance.class).name))
+            }
+            Obj::Foreign(foreign) => {
+                format!("{} instance", self.string(self.class(foreign.class).name))
+            }
+            Obj::BoundMethod(bound) => self.format(Value::obj(bound.method)),
+            Obj::Shape(_) => "shape".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intern_returns_the_same_object_for_equal_strings() {
+        let mut heap = Heap::new();
+        let a = heap.intern("hello");
+        let b = heap.intern(&format!("hel{}", "lo"));
+        let c = heap.intern("world");
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!("hello", heap.string(a));
+    }
+
+    #[test]
+    fn collect_frees_unreachable_objects() {
+        let mut heap = Heap::new();
+        let name = heap.intern("Node");
+        let shape = heap.root_shape();
+        let class = heap.alloc(Obj::Class(Class {
+            name,
+            methods: HashMap::new(),
+            shape,
+            host: None,
+        }));
+        let new_instance = |heap: &mut Heap| {
+            heap.alloc(Obj::Instance(Instance {
+                class,
+                shape,
+                fields: vec![],
+            }))
+        };
+        let a = new_instance(&mut heap);
+        let b = new_instance(&mut heap);
+        new_instance(&mut heap);
+        let next = heap.intern("next");
+        let with_next = heap.shape_transition(shape, next);
+        // a and b refer to each other, so only tracing can tell they are garbage.
+        for (from, to) in [(a, b), (b, a)] {
+            let instance = heap.instance_mut(from);
+            instance.shape = with_next;
+            instance.fields.push(Value::obj(to));
+        }
+        heap.intern("unused");
+
+        assert_eq!(2, heap.collect([a]).objects_freed);
+        assert_eq!(7, heap.object_count());
+        assert_eq!("Node instance", heap.format(Value::obj(b)));
+        assert_eq!(next, heap.intern("next"));
+        assert!(!heap.strings.contains_key("unused"));
+
+        // The class takes its shapes, and the field names they use, with it.
+        assert_eq!(7, heap.collect([]).objects_freed);
+        assert_eq!(0, heap.object_count());
+        assert_eq!(0, heap.bytes_allocated());
+        // Freed slots are reused.
+        heap.intern("again");
+        assert_eq!(1, heap.object_count());
+        assert_eq!(9, heap.objects.len());
+    }
+
+    #[test]
+    fn instances_with_the_same_fields_share_shapes() {
+        let mut heap = Heap::new();
+        let (x, y) = (heap.intern("x"), heap.intern("y"));
+        let root = heap.root_shape();
+        let xy = heap.shape_transition(root, x);
+        let xy = heap.shape_transition(xy, y);
+        let yx = heap.shape_transition(root, y);
+        let yx = heap.shape_transition(yx, x);
+
+        let again = heap.shape_transition(root, x);
+        assert_eq!(xy, heap.shape_transition(again, y));
+        assert_ne!(xy, yx);
+        assert_eq!(Some(1), heap.shape_slot(xy, y));
+        assert_eq!(Some(0), heap.shape_slot(yx, y));
+        assert_eq!(None, heap.shape_slot(root, x));
+        assert_eq!(&[y, x], heap.shape_names(yx));
+        assert_ne!(root, heap.root_shape());
+    }
+
+    #[test]
+    fn growth_thresholds() {
+        let mut heap = Heap::with_config(GcConfig {
+            initial_threshold: 0,
+            growth_factor: 4,
+            ..GcConfig::default()
+        });
+        let kept = heap.intern("kept");
+        assert!(heap.should_collect());
+        heap.collect([kept]);
+        assert_eq!(heap.bytes_allocated() * 4, heap.next_gc);
+        assert!(!heap.should_collect());
+    }
+
+    #[test]
+    fn stress_collects_after_every_allocation() {
+        let mut heap = Heap::with_config(GcConfig {
+            stress: true,
+            ..GcConfig::default()
+        });
+        assert!(!heap.should_collect());
+        let kept = heap.intern("kept");
+        assert!(heap.should_collect());
+        heap.collect([kept]);
+        assert!(!heap.should_collect());
+        heap.intern("garbage");
+        assert!(heap.should_collect());
+
+        let collection = heap.collect([kept]);
+        assert_eq!(1, collection.objects_freed);
+        assert_eq!(
+            collection.bytes_before - collection.bytes_after,
+            "garbage".len() + std::mem::size_of::<Obj>()
+        );
+        let stats = heap.stats();
+        assert_eq!(2, stats.collections);
+        assert_eq!(1, stats.objects_freed);
+        assert_eq!(
+            stats.bytes_allocated - stats.bytes_freed,
+            heap.bytes_allocated()
+        );
+    }
+
+    #[test]
+    fn format_values() {
+        let mut heap = Heap::new();
+        let name = heap.intern("Point");
+        let shape = heap.root_shape();
+        let class = heap.alloc(Obj::Class(Class {
+            name,
+            methods: HashMap::new(),
+            shape,
+            host: None,
+        }));
+        let instance = heap.alloc(Obj::Instance(Instance {
+            class,
+            shape,
+            fields: vec![],
+        }));
+        assert_eq!("nil", heap.format(Value::NIL));
+        assert_eq!("true", heap.format(Value::bool(true)));
+        assert_eq!("3", heap.format(Value::number(3.0)));
+        assert_eq!("2.5", heap.format(Value::number(2.5)));
+        assert_eq!("Point", heap.format(Value::obj(class)));
+        assert_eq!("Point instance", heap.format(Value::obj(instance)));
+    }
+}
@@ -0,0 +1,120 @@
+use crate::chunk::OpCode;
+use crate::object::*;
+use crate::value::Value;
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum OptLevel {
+    // Compile every expression and statement as written.
+    O0,
+    // Fold constant expressions and leave out code that can never run.
+    #[default]
+    O1,
+}
+
+impl OptLevel {
+    // Parses the `-O0` and `-O1` command line flags.
+    pub fn parse(flag: &str) -> Option<OptLevel> {
+        match flag {
+            "-O0" => Some(OptLevel::O0),
+            "-O1" => Some(OptLevel::O1),
+            _ => None,
+        }
+    }
+}
+
+// The value `op` leaves on the stack when applied to constant operands, or None
+// when the operation has to happen at run time, either because it can fail
+// (like `"a" - 1`) or because its operands are not known.
+pub fn fold(heap: &mut Heap, op: OpCode, operands: &[Value]) -> Option<Value> {
+    match (op, operands) {
+        (OpCode::Not, [a]) => Some(Value::bool(a.is_falsey())),
+        (OpCode::Negate, [a]) => Some(Value::number(-a.as_number()?)),
+        (OpCode::Equal, [a, b]) => Some(Value::bool(a == b)),
+        (OpCode::Add, [a, b]) => match (a.as_number(), b.as_number()) {
+            (Some(a), Some(b)) => Some(Value::number(a + b)),
+            _ => {
+                let (a, b) = (string(heap, *a)?, string(heap, *b)?);
+                Some(Value::obj(heap.intern(&(a + &b))))
+            }
+        },
+        (OpCode::Greater, [a, b]) => Some(Value::bool(a.as_number()? > b.as_number()?)),
+        (OpCode::Less, [a, b]) => Some(Value::bool(a.as_number()? < b.as_number()?)),
+        (OpCode::Subtract, [a, b]) => Some(Value::number(a.as_number()? - b.as_number()?)),
+        (OpCode::Multiply, [a, b]) => Some(Value::number(a.as_number()? * b.as_number()?)),
+        (OpCode::Divide, [a, b]) => Some(Value::number(a.as_number()? / b.as_number()?)),
+        _ => None,
+    }
+}
+
+// Number of operands `op` takes from the stack, for the operations `fold` knows.
+pub fn foldable_operands(op: OpCode) -> Option<usize> {
+    match op {
+        OpCode::Not | OpCode::Negate => Some(1),
+        OpCode::Equal
+        | OpCode::Add
+        | OpCode::Greater
+        | OpCode::Less
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide => Some(2),
+        _ => None,
+    }
+}
+
+fn string(heap: &Heap, value: Value) -> Option<String> {
+    match heap.get(value.as_obj()?) {
+        Obj::String(string) => Some(string.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fold_arithmetic_and_comparisons() {
+        let mut heap = Heap::new();
+        let (two, three) = (Value::number(2.0), Value::number(3.0));
+        let cases = [
+            (OpCode::Add, Value::number(5.0)),
+            (OpCode::Subtract, Value::number(-1.0)),
+            (OpCode::Multiply, Value::number(6.0)),
+            (OpCode::Divide, Value::number(2.0 / 3.0)),
+            (OpCode::Greater, Value::bool(false)),
+            (OpCode::Less, Value::bool(true)),
+            (OpCode::Equal, Value::bool(false)),
+        ];
+        for (op, expected) in cases {
+            assert_eq!(Some(expected), fold(&mut heap, op, &[two, three]));
+        }
+        assert_eq!(
+            Some(Value::number(-2.0)),
+            fold(&mut heap, OpCode::Negate, &[two])
+        );
+        assert_eq!(
+            Some(Value::bool(true)),
+            fold(&mut heap, OpCode::Not, &[Value::NIL])
+        );
+    }
+
+    #[test]
+    fn fold_string_concatenation() {
+        let mut heap = Heap::new();
+        let (a, b) = (heap.intern("a"), heap.intern("b"));
+        let folded = fold(&mut heap, OpCode::Add, &[Value::obj(a), Value::obj(b)]);
+        assert_eq!(Some(Value::obj(heap.intern("ab"))), folded);
+    }
+
+    #[test]
+    fn leave_failing_operations_to_run_time() {
+        let mut heap = Heap::new();
+        let a = Value::obj(heap.intern("a"));
+        let one = Value::number(1.0);
+        assert_eq!(None, fold(&mut heap, OpCode::Subtract, &[a, one]));
+        assert_eq!(None, fold(&mut heap, OpCode::Add, &[a, one]));
+        assert_eq!(None, fold(&mut heap, OpCode::Less, &[one, Value::NIL]));
+        assert_eq!(None, fold(&mut heap, OpCode::Negate, &[a]));
+        assert_eq!(None, fold(&mut heap, OpCode::Print, &[one]));
+    }
+}
@@ -0,0 +1,753 @@
+use crate::ast::*;
+use crate::error::*;
+use crate::token::*;
+
+const MAX_ARGUMENTS: usize = 255;
+// How deeply statements, function bodies and expressions may nest, as in the
+// compiler, so that the recursive descent cannot overflow the stack.
+const MAX_NESTING: usize = 255;
+
+pub struct Parser {
+    tokens: Vec<Token>,
+    current: usize,
+    errors: Vec<Error>,
+    depth: usize,
+    // Set once the code nests too deeply. The rest of it is skipped, as the
+    // enclosing constructs would only report their missing ends.
+    too_deep: bool,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Token>) -> Self {
+        Parser {
+            tokens,
+            current: 0,
+            errors: vec![],
+            depth: 0,
+            too_deep: false,
+        }
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current]
+    }
+
+    fn previous(&self) -> &Token {
+        &self.tokens[self.current - 1]
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.peek().token_type == TokenType::EOF
+    }
+
+    fn advance(&mut self) -> &Token {
+        if !self.is_at_end() {
+            self.current += 1;
+        }
+        self.previous()
+    }
+
+    fn check(&self, token_type: &TokenType) -> bool {
+        !self.is_at_end() && self.peek().token_type == *token_type
+    }
+
+    fn match_next(&mut self, token_types: &[TokenType]) -> bool {
+        for token_type in token_types {
+            if self.check(token_type) {
+                self.advance();
+                return true;
+            }
+        }
+        false
+    }
+
+    fn match_keyword(&mut self, keyword: Keyword) -> bool {
+        self.match_next(&[TokenType::Keyword(keyword)])
+    }
+
+    fn error(&self, token: &Token, msg: &str) -> Error {
+        Error::Parse {
+            span: token.span,
+            lexeme: (token.token_type != TokenType::EOF).then(|| token.lexeme.clone()),
+            message: msg.to_string(),
+        }
+    }
+
+    fn consume(&mut self, token_type: TokenType, msg: &str) -> Result<Token, Error> {
+        if self.check(&token_type) {
+            Ok(self.advance().clone())
+        } else {
+            Err(self.error(self.peek(), msg))
+        }
+    }
+
+    fn consume_identifier(&mut self, msg: &str) -> Result<Token, Error> {
+        if let TokenType::Identifier(_) = self.peek().token_type {
+            Ok(self.advance().clone())
+        } else {
+            Err(self.error(self.peek(), msg))
+        }
+    }
+
+    // Discards tokens until the start of the next statement, so parsing can resume after an error.
+    // Parses one level deeper, unless that is too deep.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        if self.depth == MAX_NESTING {
+            // The error is recorded here, and the copy returned unwinds the
+            // constructs in progress.
+            let error = self.error(self.peek(), "Too much nesting.");
+            if !self.too_deep {
+                self.too_deep = true;
+                self.errors
+                    .push(self.error(self.peek(), "Too much nesting."));
+                self.current = self.tokens.len() - 1;
+            }
+            return Err(error);
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn synchronize(&mut self) {
+        self.advance();
+        while !self.is_at_end() {
+            if self.previous().token_type == TokenType::Semicolon {
+                return;
+            }
+            match self.peek().token_type {
+                TokenType::Keyword(Keyword::Class)
+                | TokenType::Keyword(Keyword::Fun)
+                | TokenType::Keyword(Keyword::Var)
+                | TokenType::Keyword(Keyword::For)
+                | TokenType::Keyword(Keyword::If)
+                | TokenType::Keyword(Keyword::While)
+                | TokenType::Keyword(Keyword::Print)
+                | TokenType::Keyword(Keyword::Return) => return,
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    fn declaration(&mut self) -> Option<Stmt> {
+        match self.try_declaration() {
+            Ok(statement) => Some(statement),
+            Err(error) => {
+                if !self.too_deep {
+                    self.errors.push(error);
+                }
+                self.synchronize();
+                None
+            }
+        }
+    }
+
+    fn try_declaration(&mut self) -> Result<Stmt, Error> {
+        if self.match_keyword(Keyword::Class) {
+            self.class_declaration()
+        } else if self.match_keyword(Keyword::Fun) {
+            Ok(Stmt::Function(self.function("function")?))
+        } else if self.match_keyword(Keyword::Var) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        }
+    }
+
+    fn class_declaration(&mut self) -> Result<Stmt, Error> {
+        let name = self.consume_identifier("Expect class name.")?;
+        let superclass = if self.match_next(&[TokenType::Less]) {
+            let name = self.consume_identifier("Expect superclass name.")?;
+            Some(Expr::Variable { name })
+        } else {
+            None
+        };
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
+        let mut methods = vec![];
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            methods.push(self.function("method")?);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
+        Ok(Stmt::Class {
+            name,
+            superclass,
+            methods,
+        })
+    }
+
+    fn function(&mut self, kind: &str) -> Result<Function, Error> {
+        let name = self.consume_identifier(&format!("Expect {} name.", kind))?;
+        self.consume(
+            TokenType::LeftParenthesis,
+            &format!("Expect '(' after {} name.", kind),
+        )?;
+        let mut params = vec![];
+        if !self.check(&TokenType::RightParenthesis) {
+            loop {
+                if params.len() == MAX_ARGUMENTS {
+                    let error = self.error(self.peek(), "Can't have more than 255 parameters.");
+                    self.errors.push(error);
+                }
+                params.push(self.consume_identifier("Expect parameter name.")?);
+                if !self.match_next(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParenthesis, "Expect ')' after parameters.")?;
+        self.consume(
+            TokenType::LeftBrace,
+            &format!("Expect '{{' before {} body.", kind),
+        )?;
+        let body = self.nested(Self::block)?;
+        Ok(Function { name, params, body })
+    }
+
+    fn var_declaration(&mut self) -> Result<Stmt, Error> {
+        let name = self.consume_identifier("Expect variable name.")?;
+        let initializer = if self.match_next(&[TokenType::Equal]) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        )?;
+        Ok(Stmt::Var { name, initializer })
+    }
+
+    fn statement(&mut self) -> Result<Stmt, Error> {
+        self.nested(|parser| {
+            if parser.match_keyword(Keyword::For) {
+                parser.for_statement()
+            } else if parser.match_keyword(Keyword::If) {
+                parser.if_statement()
+            } else if parser.match_keyword(Keyword::Print) {
+                parser.print_statement()
+            } else if parser.match_keyword(Keyword::Return) {
+                parser.return_statement()
+            } else if parser.match_keyword(Keyword::While) {
+                parser.while_statement()
+            } else if parser.match_next(&[TokenType::LeftBrace]) {
+                Ok(Stmt::Block {
+                    statements: parser.block()?,
+                })
+            } else {
+                parser.expression_statement()
+            }
+        })
+    }
+
+    // A for loop is desugared into a while loop wrapped in blocks.
+    fn for_statement(&mut self) -> Result<Stmt, Error> {
+        self.consume(TokenType::LeftParenthesis, "Expect '(' after 'for'.")?;
+        let initializer = if self.match_next(&[TokenType::Semicolon]) {
+            None
+        } else if self.match_keyword(Keyword::Var) {
+            Some(self.var_declaration()?)
+        } else {
+            Some(self.expression_statement()?)
+        };
+
+        let condition = if self.check(&TokenType::Semicolon) {
+            Expr::Literal {
+                value: Literal::Bool(true),
+            }
+        } else {
+            self.expression()?
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after loop condition.")?;
+
+        let increment = if self.check(&TokenType::RightParenthesis) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::RightParenthesis, "Expect ')' after for clauses.")?;
+
+        let mut body = self.statement()?;
+        if let Some(increment) = increment {
+            body = Stmt::Block {
+                statements: vec![
+                    body,
+                    Stmt::Expression {
+                        expression: increment,
+                    },
+                ],
+            };
+        }
+        body = Stmt::While {
+            condition,
+            body: Box::new(body),
+        };
+        if let Some(initializer) = initializer {
+            body = Stmt::Block {
+                statements: vec![initializer, body],
+            };
+        }
+        Ok(body)
+    }
+
+    fn if_statement(&mut self) -> Result<Stmt, Error> {
+        self.consume(TokenType::LeftParenthesis, "Expect '(' after 'if'.")?;
+        let condition = self.expression()?;
+        self.consume(
+            TokenType::RightParenthesis,
+            "Expect ')' after if condition.",
+        )?;
+        let then_branch = Box::new(self.statement()?);
+        let else_branch = if self.match_keyword(Keyword::Else) {
+            Some(Box::new(self.statement()?))
+        } else {
+            None
+        };
+        Ok(Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+        })
+    }
+
+    fn print_statement(&mut self) -> Result<Stmt, Error> {
+        let expression = self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
+        Ok(Stmt::Print { expression })
+    }
+
+    fn return_statement(&mut self) -> Result<Stmt, Error> {
+        let keyword = self.previous().clone();
+        let value = if self.check(&TokenType::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after return value.")?;
+        Ok(Stmt::Return { keyword, value })
+    }
+
+    fn while_statement(&mut self) -> Result<Stmt, Error> {
+        self.consume(TokenType::LeftParenthesis, "Expect '(' after 'while'.")?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParenthesis, "Expect ')' after condition.")?;
+        let body = Box::new(self.statement()?);
+        Ok(Stmt::While { condition, body })
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, Error> {
+        let mut statements = vec![];
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            if let Some(statement) = self.declaration() {
+                statements.push(statement);
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.")?;
+        Ok(statements)
+    }
+
+    fn expression_statement(&mut self) -> Result<Stmt, Error> {
+        let expression = self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.")?;
+        Ok(Stmt::Expression { expression })
+    }
+
+    fn expression(&mut self) -> Result<Expr, Error> {
+        self.nested(Self::assignment)
+    }
+
+    fn assignment(&mut self) -> Result<Expr, Error> {
+        let expr = self.or()?;
+        if self.match_next(&[TokenType::Equal]) {
+            let equals = self.previous().clone();
+            let value = Box::new(self.nested(Self::assignment)?);
+            return match expr {
+                Expr::Variable { name } => Ok(Expr::Assign { name, value }),
+                Expr::Get { object, name } => Ok(Expr::Set {
+                    object,
+                    name,
+                    value,
+                }),
+                // The target is reported but needs no recovery, parsing just carries on.
+                expr => {
+                    let error = self.error(&equals, "Invalid assignment target.");
+                    self.errors.push(error);
+                    Ok(expr)
+                }
+            };
+        }
+        Ok(expr)
+    }
+
+    fn or(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.and()?;
+        while self.match_keyword(Keyword::Or) {
+            let operator = self.previous().clone();
+            let right = self.and()?;
+            expr = Expr::Logical {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.equality()?;
+        while self.match_keyword(Keyword::And) {
+            let operator = self.previous().clone();
+            let right = self.equality()?;
+            expr = Expr::Logical {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+
+    fn binary(
+        &mut self,
+        token_types: &[TokenType],
+        operand: fn(&mut Self) -> Result<Expr, Error>,
+    ) -> Result<Expr, Error> {
+        let mut expr = operand(self)?;
+        while self.match_next(token_types) {
+            let operator = self.previous().clone();
+            let right = operand(self)?;
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+
+    fn equality(&mut self) -> Result<Expr, Error> {
+        self.binary(
+            &[TokenType::BangEqual, TokenType::EqualEqual],
+            Self::comparison,
+        )
+    }
+
+    fn comparison(&mut self) -> Result<Expr, Error> {
+        self.binary(
+            &[
+                TokenType::Greater,
+                TokenType::GreaterEqual,
+                TokenType::Less,
+                TokenType::LessEqual,
+            ],
+            Self::term,
+        )
+    }
+
+    fn term(&mut self) -> Result<Expr, Error> {
+        self.binary(&[TokenType::Minus, TokenType::Plus], Self::factor)
+    }
+
+    fn factor(&mut self) -> Result<Expr, Error> {
+        self.binary(&[TokenType::Slash, TokenType::Star], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<Expr, Error> {
+        if self.match_next(&[TokenType::Bang, TokenType::Minus]) {
+            let operator = self.previous().clone();
+            let right = self.nested(Self::unary)?;
+            return Ok(Expr::Unary {
+                operator,
+                right: Box::new(right),
+            });
+        }
+        self.call()
+    }
+
+    fn call(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.match_next(&[TokenType::LeftParenthesis]) {
+                expr = self.finish_call(expr)?;
+            } else if self.match_next(&[TokenType::Dot]) {
+                let name = self.consume_identifier("Expect property name after '.'.")?;
+                expr = Expr::Get {
+                    object: Box::new(expr),
+                    name,
+                };
+            } else {
+                break;
+            }
+        }
+        Ok(expr)
+    }
+
+    fn finish_call(&mut self, callee: Expr) -> Result<Expr, Error> {
+        let mut arguments = vec![];
+        if !self.check(&TokenType::RightParenthesis) {
+            loop {
+                if arguments.len() == MAX_ARGUMENTS {
+                    let error = self.error(self.peek(), "Can't have more than 255 arguments.");
+                    self.errors.push(error);
+                }
+                arguments.push(self.expression()?);
+                if !self.match_next(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        let paren = self.consume(TokenType::RightParenthesis, "Expect ')' after arguments.")?;
+        Ok(Expr::Call {
+            callee: Box::new(callee),
+            paren,
+            arguments,
+        })
+    }
+
+    fn primary(&mut self) -> Result<Expr, Error> {
+        let token = self.peek().clone();
+        let expr = match token.token_type {
+            TokenType::Keyword(Keyword::False) => Expr::Literal {
+                value: Literal::Bool(false),
+            },
+            TokenType::Keyword(Keyword::True) => Expr::Literal {
+                value: Literal::Bool(true),
+            },
+            TokenType::Keyword(Keyword::Nil) => Expr::Literal {
+                value: Literal::Nil,
+            },
+            TokenType::Number(number) => Expr::Literal {
+                value: Literal::Number(number),
+            },
+            TokenType::String(ref string) => Expr::Literal {
+                value: Literal::String(string.clone()),
+            },
+            TokenType::Keyword(Keyword::Super) => {
+                self.advance();
+                self.consume(TokenType::Dot, "Expect '.' after 'super'.")?;
+                let method = self.consume_identifier("Expect superclass method name.")?;
+                return Ok(Expr::Super {
+                    keyword: token,
+                    method,
+                });
+            }
+            TokenType::Keyword(Keyword::This) => Expr::This { keyword: token },
+            TokenType::Identifier(_) => Expr::Variable { name: token },
+            TokenType::LeftParenthesis => {
+                self.advance();
+                let expression = Box::new(self.expression()?);
+                self.consume(TokenType::RightParenthesis, "Expect ')' after expression.")?;
+                return Ok(Expr::Grouping { expression });
+            }
+            _ => return Err(self.error(&token, "Expect expression.")),
+        };
+        self.advance();
+        Ok(expr)
+    }
+
+    pub fn parse(&mut self) -> Result<Vec<Stmt>, Vec<Error>> {
+        self.current = 0;
+        self.errors = vec![];
+        self.depth = 0;
+        self.too_deep = false;
+        let mut statements = vec![];
+        while !self.is_at_end() {
+            if let Some(statement) = self.declaration() {
+                statements.push(statement);
+            }
+        }
+        if self.errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    pub fn parse_expression(&mut self) -> Result<Expr, Error> {
+        self.current = 0;
+        self.errors = vec![];
+        self.depth = 0;
+        self.too_deep = false;
+        let expr = self.expression()?;
+        if !self.is_at_end() {
+            return Err(self.error(self.peek(), "Expect end of expression."));
+        }
+        match self.errors.drain(..).next() {
+            Some(error) => Err(error),
+            None => Ok(expr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::*;
+
+    fn parser(code: &str) -> Parser {
+        let mut scanner = Scanner::new(code.to_string());
+        scanner.scan().unwrap();
+        Parser::new(scanner.tokens)
+    }
+
+    // A token on the first line, starting at `column`.
+    fn token(token_type: TokenType, lexeme: &str, column: u64) -> Token {
+        Token {
+            token_type,
+            lexeme: lexeme.to_string(),
+            line: 1,
+            span: Span {
+                line: 1,
+                column,
+                length: lexeme.chars().count() as u64,
+            },
+        }
+    }
+
+    fn number(value: f64) -> Box<Expr> {
+        Box::new(Expr::Literal {
+            value: Literal::Number(value),
+        })
+    }
+
+    #[test]
+    fn parse_respects_precedence() {
+        let expr = parser("1 + 2 * 3").parse_expression().unwrap();
+        let expected = Expr::Binary {
+            left: number(1.0),
+            operator: token(TokenType::Plus, "+", 3),
+            right: Box::new(Expr::Binary {
+                left: number(2.0),
+                operator: token(TokenType::Star, "*", 7),
+                right: number(3.0),
+            }),
+        };
+        assert_eq!(expected, expr);
+    }
+
+    #[test]
+    fn parse_assignment_targets() {
+        let expr = parser("a.b = c = 1").parse_expression().unwrap();
+        let expected = Expr::Set {
+            object: Box::new(Expr::Variable {
+                name: token(TokenType::Identifier("a".to_string()), "a", 1),
+            }),
+            name: token(TokenType::Identifier("b".to_string()), "b", 3),
+            value: Box::new(Expr::Assign {
+                name: token(TokenType::Identifier("c".to_string()), "c", 7),
+                value: number(1.0),
+            }),
+        };
+        assert_eq!(expected, expr);
+
+        assert!(parser("1 = 2").parse_expression().is_err());
+    }
+
+    #[test]
+    fn parse_desugars_for_loop() {
+        let statements = parser("for (;;) print 1;").parse().unwrap();
+        let expected = vec![Stmt::While {
+            condition: Expr::Literal {
+                value: Literal::Bool(true),
+            },
+            body: Box::new(Stmt::Print {
+                expression: *number(1.0),
+            }),
+        }];
+        assert_eq!(expected, statements);
+    }
+
+    fn parse_errors(code: &str) -> Vec<String> {
+        match parser(code).parse() {
+            Err(errors) => errors
+                .into_iter()
+                .map(|error| match error {
+                    Error::Parse { .. } => error.to_string(),
+                    _ => panic!("expected a parse error"),
+                })
+                .collect(),
+            Ok(_) => panic!("expected parse errors"),
+        }
+    }
+
+    #[test]
+    fn parse_missing_semicolon_returns_error() {
+        assert_eq!(
+            vec!["[line 2] Parse error at 'print': Expect ';' after value."],
+            parse_errors("print 1\nprint 2;")
+        );
+        assert_eq!(
+            vec!["[line 1] Parse error at end: Expect ')' after expression."],
+            parse_errors("var a = (1")
+        );
+    }
+
+    #[test]
+    fn parse_reports_every_syntax_error() {
+        let code = "var = 1;
+print 2;
+fun f() {
+  var a = ;
+  1 = a;
+  return a
+  print a;
+}
+class { }
+while (true) print 3;";
+        let expected = vec![
+            "[line 1] Parse error at '=': Expect variable name.",
+            "[line 4] Parse error at ';': Expect expression.",
+            "[line 5] Parse error at '=': Invalid assignment target.",
+            "[line 7] Parse error at 'print': Expect ';' after return value.",
+            "[line 9] Parse error at '{': Expect class name.",
+        ];
+        assert_eq!(expected, parse_errors(code));
+    }
+
+    #[test]
+    fn parse_synchronizes_at_statement_keywords() {
+        let expected = vec![
+            "[line 1] Parse error at 'var': Expect ')' after expression.",
+            "[line 2] Parse error at 'print': Expect ';' after value.",
+        ];
+        assert_eq!(
+            expected,
+            parse_errors("print (1 var a = 1 if (a) print a\nprint a;")
+        );
+    }
+
+    #[test]
+    fn parse_limits_nesting() {
+        let expected = vec!["[line 1] Parse error at '-': Too much nesting."];
+        let code = format!("print {}1;", "-".repeat(50_000));
+        assert_eq!(expected, parse_errors(&code));
+
+        let expected = vec!["[line 1] Parse error at '{': Too much nesting."];
+        assert_eq!(expected, parse_errors(&"{".repeat(300)));
+
+        let code = format!("{}print 1;", "if (true) ".repeat(300));
+        assert_eq!(1, parse_errors(&code).len());
+
+        let code = format!("{}{}", "fun f() {".repeat(300), "}".repeat(300));
+        assert_eq!(1, parse_errors(&code).len());
+
+        let code = format!("print {}1{};", "(".repeat(50), ")".repeat(50));
+        assert!(parser(&code).parse().is_ok());
+    }
+
+    #[test]
+    fn parse_expression_limits_nesting() {
+        let code = format!("{}1", "-".repeat(50_000));
+        match parser(&code).parse_expression() {
+            Err(error) => {
+                assert_eq!(
+                    "[line 1] Parse error at '-': Too much nesting.",
+                    error.to_string()
+                )
+            }
+            Ok(_) => panic!("expected a parse error"),
+        }
+    }
+}
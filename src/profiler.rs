@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::object::*;
+
+// Time is sampled every this many instructions. The time since the previous
+// sample is charged to the instruction running when the sample is taken.
+const SAMPLE_INTERVAL: u32 = 64;
+
+// Number of lines listed in the report.
+const REPORT_LINES: usize = 20;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    instructions: u64,
+    calls: u64,
+    time: Duration,
+}
+
+// Execution counts and sampled time per function, per line and per call stack.
+// Functions are identified by their object, which the VM keeps alive for as
+// long as the profile refers to it.
+pub struct Profiler {
+    functions: HashMap<ObjRef, Counts>,
+    lines: HashMap<(ObjRef, u64), Counts>,
+    stacks: HashMap<Vec<ObjRef>, Duration>,
+    last_sample: Instant,
+    until_sample: u32,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            functions: HashMap::new(),
+            lines: HashMap::new(),
+            stacks: HashMap::new(),
+            last_sample: Instant::now(),
+            until_sample: SAMPLE_INTERVAL,
+        }
+    }
+
+    // Starts timing again, so that time spent outside the VM is not charged.
+    pub fn resume(&mut self) {
+        self.last_sample = Instant::now();
+        self.until_sample = SAMPLE_INTERVAL;
+    }
+
+    pub fn call(&mut self, function: ObjRef) {
+        self.functions.entry(function).or_default().calls += 1;
+    }
+
+    // Counts an instruction of `function` on `line`. `stack` gives the functions
+    // of the active call frames, outermost first, when a sample is taken.
+    pub fn instruction<F>(&mut self, function: ObjRef, line: u64, stack: F)
+    where
+        F: FnOnce() -> Vec<ObjRef>,
+    {
+        let counts = self.functions.entry(function).or_default();
+        counts.instructions += 1;
+        let line_counts = self.lines.entry((function, line)).or_default();
+        line_counts.instructions += 1;
+
+        self.until_sample -= 1;
+        if self.until_sample == 0 {
+            self.until_sample = SAMPLE_INTERVAL;
+            let now = Instant::now();
+            let elapsed = now - self.last_sample;
+            self.last_sample = now;
+            line_counts.time += elapsed;
+            self.functions.get_mut(&function).unwrap().time += elapsed;
+            *self.stacks.entry(stack()).or_default() += elapsed;
+        }
+    }
+
+    // Every function the profile refers to, as roots for the collector.
+    pub fn functions(&self) -> impl Iterator<Item = ObjRef> + '_ {
+        self.functions.keys().copied()
+    }
+
+    // The functions and then the lines that took the most time, with their counts.
+    pub fn report(&self, heap: &Heap) -> String {
+        let total: Duration = self.functions.values().map(|counts| counts.time).sum();
+        let percent = |time: Duration| match total.as_secs_f64() {
+            0.0 => 0.0,
+            total => 100.0 * time.as_secs_f64() / total,
+        };
+
+        let mut out = format!(
+            "[profile] {:>10} {:>6} {:>10} {:>13}  function\n",
+            "time", "%", "calls", "instructions"
+        );
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort_by(|a, b| {
+            b.1.time
+                .cmp(&a.1.time)
+                .then(b.1.instructions.cmp(&a.1.instructions))
+        });
+        for (function, counts) in functions {
+            writeln!(
+                out,
+                "[profile] {:>10.2?} {:>5.1}% {:>10} {:>13}  {}",
+                counts.time,
+                percent(counts.time),
+                counts.calls,
+                counts.instructions,
+                label(heap, *function)
+            )
+            .unwrap();
+        }
+
+        write!(
+            out,
+            "[profile] {:>10} {:>6} {:>10} {:>13}  line",
+            "time", "%", "", "instructions"
+        )
+        .unwrap();
+        let mut lines: Vec<_> = self.lines.iter().collect();
+        lines.sort_by(|a, b| {
+            b.1.time
+                .cmp(&a.1.time)
+                .then(b.1.instructions.cmp(&a.1.instructions))
+        });
+        for ((function, line), counts) in lines.into_iter().take(REPORT_LINES) {
+            write!(
+                out,
+                "\n[profile] {:>10.2?} {:>5.1}% {:>10} {:>13}  {} in {}",
+                counts.time,
+                percent(counts.time),
+                "",
+                counts.instructions,
+                line,
+                name(heap, *function)
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    // One line per sampled call stack, its frames separated by semicolons and
+    // followed by the time spent in it in microseconds, as read by flame graph
+    // tools.
+    pub fn folded_stacks(&self, heap: &Heap) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .filter(|(_, time)| time.as_micros() > 0)
+            .map(|(stack, time)| {
+                let frames: Vec<String> = stack
+                    .iter()
+                    .map(|function| label(heap, *function))
+                    .collect();
+                format!("{} {}", frames.join(";"), time.as_micros())
+            })
+            .collect();
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
+
+fn name(heap: &Heap, function: ObjRef) -> String {
+    match heap.function(function).name {
+        Some(name) => heap.string(name).to_string(),
+        None => "script".to_string(),
+    }
+}
+
+// Names are not unique, so functions are told apart by the first line of their code.
+fn label(heap: &Heap, function: ObjRef) -> String {
+    match heap.function(function).chunk.lines.first() {
+        Some(line) if heap.function(function).name.is_some() => {
+            format!("{}:{}", name(heap, function), line)
+        }
+        _ => name(heap, function),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+    use std::rc::Rc;
+
+    fn function(heap: &mut Heap, name: Option<&str>, line: u64) -> ObjRef {
+        let mut chunk = Chunk::new();
+        chunk.lines.push(line);
+        let name = name.map(|name| heap.intern(name));
+        heap.alloc(Obj::Function(Function {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Rc::new(chunk),
+            name,
+        }))
+    }
+
+    #[test]
+    fn count_instructions_and_sample_stacks() {
+        let mut heap = Heap::new();
+        let script = function(&mut heap, None, 1);
+        let fib = function(&mut heap, Some("fib"), 2);
+        let mut profiler = Profiler::new();
+        profiler.call(script);
+        profiler.call(fib);
+        for i in 0..SAMPLE_INTERVAL * 3 {
+            if i % 2 == 0 {
+                std::thread::sleep(Duration::from_micros(10));
+            }
+            profiler.instruction(fib, 3, || vec![script, fib]);
+        }
+        profiler.instruction(script, 7, || vec![script]);
+
+        assert_eq!(1, profiler.functions[&fib].calls);
+        assert_eq!(
+            3 * SAMPLE_INTERVAL as u64,
+            profiler.functions[&fib].instructions
+        );
+        assert_eq!(1, profiler.lines[&(script, 7)].instructions);
+        assert!(profiler.functions[&fib].time >= Duration::from_micros(10 * 96));
+        assert_eq!(Duration::ZERO, profiler.functions[&script].time);
+        assert_eq!(2, profiler.functions().count());
+
+        let report = profiler.report(&heap);
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(
+            lines[1].ends_with("100.0%          1           192  fib:2"),
+            "{}",
+            lines[1]
+        );
+        assert!(
+            lines[2].ends_with("0.0%          1             1  script"),
+            "{}",
+            lines[2]
+        );
+        assert!(lines[4].ends_with("192  3 in fib"), "{}", lines[4]);
+        assert!(lines[5].ends_with("1  7 in script"), "{}", lines[5]);
+
+        let folded = profiler.folded_stacks(&heap);
+        assert!(folded.starts_with("script;fib:2 "), "{}", folded);
+        assert_eq!(1, folded.lines().count());
+    }
+}
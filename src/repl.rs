@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+use rlox::ast_printer::AstFormat;
+use rlox::scanner::KEYWORD_MAP;
+use rlox::vm::{InterruptHandle, Options, Vm};
+
+use crate::cli;
+
+const COMMANDS: [&str; 7] = [
+    ":tokens", ":ast", ":env", ":load", ":reset", ":time", ":help",
+];
+
+const HELP: &str = "\
+:tokens <code>  print the tokens scanned from <code>
+:ast <code>     print the syntax tree parsed from <code>
+:env            list the global variables of the session
+:load <file>    run <file> in the current session
+:reset          reset the session to its initial state
+:time           toggle reporting the time taken by each evaluation
+:help           show this message";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Tokens(String),
+    Ast(String),
+    Env,
+    Load(String),
+    Reset,
+    Time,
+    Help,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let line = line.trim();
+        let (name, argument) = match line.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (line, ""),
+        };
+        match (name, argument.is_empty()) {
+            (":tokens", false) => Ok(Command::Tokens(argument.to_string())),
+            (":ast", false) => Ok(Command::Ast(argument.to_string())),
+            (":load", false) => Ok(Command::Load(argument.to_string())),
+            (":env", true) => Ok(Command::Env),
+            (":reset", true) => Ok(Command::Reset),
+            (":time", true) => Ok(Command::Time),
+            (":help", true) => Ok(Command::Help),
+            (":tokens", true) | (":ast", true) | (":load", true) => {
+                Err(format!("{} expects an argument.", name))
+            }
+            (":env", false) | (":reset", false) | (":time", false) | (":help", false) => {
+                Err(format!("{} takes no arguments.", name))
+            }
+            _ => Err(format!("Unknown command {}, try :help.", name)),
+        }
+    }
+}
+
+// Start of the word, identifier or command name, that `text` ends with.
+fn word_start(text: &str) -> usize {
+    text.char_indices()
+        .rev()
+        .find(|(_, c)| !(c.is_alphanumeric() || *c == '_' || *c == ':'))
+        .map_or(0, |(i, c)| i + c.len_utf8())
+}
+
+#[derive(Default)]
+pub struct LoxHelper {
+    globals: Vec<String>,
+    // Field and method names of the instances held in globals, by global name.
+    members: HashMap<String, Vec<String>>,
+}
+
+impl LoxHelper {
+    pub fn refresh(&mut self, vm: &Vm) {
+        self.globals = vm.globals().into_iter().map(|(name, _)| name).collect();
+        self.members = self
+            .globals
+            .iter()
+            .map(|name| (name.clone(), vm.members(name)))
+            .filter(|(_, members)| !members.is_empty())
+            .collect();
+    }
+
+    fn candidates(&self, line: &str, start: usize) -> Vec<String> {
+        let before = line[..start].trim_end();
+        if let Some(object) = before.strip_suffix('.') {
+            let object = &object[word_start(object)..];
+            self.members.get(object).cloned().unwrap_or_default()
+        } else if before.is_empty() && line.trim_start().starts_with(':') {
+            COMMANDS.iter().map(|command| command.to_string()).collect()
+        } else {
+            KEYWORD_MAP
+                .keys()
+                .map(|keyword| keyword.to_string())
+                .chain(self.globals.iter().cloned())
+                .collect()
+        }
+    }
+}
+
+impl Completer for LoxHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = word_start(&line[..pos]);
+        let prefix = &line[start..pos];
+        let mut matches: Vec<String> = self
+            .candidates(line, start)
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(prefix))
+            .collect();
+        matches.sort();
+        Ok((start, matches))
+    }
+}
+
+impl Hinter for LoxHelper {
+    type Hint = String;
+}
+
+impl Highlighter for LoxHelper {}
+
+impl Validator for LoxHelper {}
+
+impl Helper for LoxHelper {}
+
+// A process has a single Ctrl-C handler, so every session stops its
+// evaluations through this one handle.
+fn ctrl_c_handle() -> InterruptHandle {
+    static HANDLE: OnceLock<InterruptHandle> = OnceLock::new();
+    HANDLE.get_or_init(InterruptHandle::new).clone()
+}
+
+pub struct Repl {
+    timing: bool,
+    options: Options,
+    vm: Vm,
+    // Ctrl-C stops the evaluation in progress through this handle, which the
+    // session keeps across resets.
+    interrupt: InterruptHandle,
+}
+
+impl Repl {
+    pub fn new(options: Options) -> Self {
+        let interrupt = ctrl_c_handle();
+        Repl {
+            timing: false,
+            options,
+            vm: Repl::new_vm(options, interrupt.clone()),
+            interrupt,
+        }
+    }
+
+    fn new_vm(options: Options, interrupt: InterruptHandle) -> Vm {
+        let mut vm = Vm::new();
+        vm.set_options(options);
+        vm.set_interrupt_handle(interrupt);
+        vm
+    }
+
+    pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut editor: Editor<LoxHelper, DefaultHistory> = Editor::new()?;
+        // While a line is edited the terminal reads Ctrl-C as a key, so the
+        // signal only arrives during evaluations. The handler replaces the one
+        // the editor installs, so it has to come after it. A later session
+        // finds the handler of an earlier one, which uses the same handle.
+        let interrupt = self.interrupt.clone();
+        match ctrlc::set_handler(move || interrupt.interrupt()) {
+            Ok(()) | Err(ctrlc::Error::MultipleHandlers) => {}
+            Err(error) => return Err(Box::new(error)),
+        }
+        editor.set_helper(Some(LoxHelper::default()));
+        loop {
+            let buffer = match editor.readline("> ") {
+                Ok(line) => line,
+                // Ctrl-C at the prompt only discards the line being edited.
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => {
+                    if let Some(report) = self.vm.gc_report() {
+                        eprintln!("{}", report);
+                    }
+                    if let Some(report) = self.vm.profile_report() {
+                        eprintln!("{}", report);
+                    }
+                    return Ok(());
+                }
+                Err(error) => return Err(Box::new(error)),
+            };
+            if buffer.trim_start().starts_with(':') {
+                match Command::parse(&buffer) {
+                    Ok(command) => self.execute(command),
+                    Err(msg) => println!("{}", msg),
+                }
+            } else {
+                self.eval(buffer, None);
+            }
+            if let Some(helper) = editor.helper_mut() {
+                helper.refresh(&self.vm);
+            }
+        }
+    }
+
+    fn execute(&mut self, command: Command) {
+        match command {
+            Command::Tokens(code) => match cli::scan(code) {
+                Ok(tokens) => {
+                    for token in tokens.iter() {
+                        println!("{:?}", token);
+                    }
+                }
+                Err(error) => println!("{}", error),
+            },
+            Command::Ast(code) => match cli::print_ast(code, AstFormat::Tree) {
+                Ok(ast) => println!("{}", ast),
+                Err(error) => println!("{}", error),
+            },
+            Command::Env => {
+                for (name, value) in self.vm.globals() {
+                    println!("{} = {}", name, value);
+                }
+            }
+            Command::Load(file_name) => match std::fs::read_to_string(&file_name) {
+                Ok(code) => self.eval(code, Some(&file_name)),
+                Err(error) => println!("Could not read {}: {}", file_name, error),
+            },
+            Command::Reset => {
+                self.timing = false;
+                self.vm = Repl::new_vm(self.options, self.interrupt.clone());
+            }
+            Command::Time => {
+                self.timing = !self.timing;
+                println!("Timing {}.", if self.timing { "on" } else { "off" });
+            }
+            Command::Help => println!("{}", HELP),
+        }
+    }
+
+    fn eval(&mut self, code: String, file_name: Option<&str>) {
+        let start = Instant::now();
+        let result = match file_name {
+            Some(file_name) => cli::load(&mut self.vm, file_name, code),
+            None => cli::run(&mut self.vm, code),
+        };
+        if self.timing {
+            println!("Took {:?}.", start.elapsed());
+        }
+        if let Err(error) = result {
+            println!("{}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            Ok(Command::Tokens("var a = 1;".to_string())),
+            Command::parse(":tokens var a = 1;\n")
+        );
+        assert_eq!(
+            Ok(Command::Ast("1 + 2".to_string())),
+            Command::parse(":ast 1 + 2")
+        );
+        assert_eq!(
+            Ok(Command::Load("script.lox".to_string())),
+            Command::parse(":load  script.lox")
+        );
+        assert_eq!(Ok(Command::Env), Command::parse(":env"));
+        assert_eq!(Ok(Command::Reset), Command::parse(":reset"));
+        assert_eq!(Ok(Command::Time), Command::parse(" :time\n"));
+        assert_eq!(Ok(Command::Help), Command::parse(":help"));
+    }
+
+    #[test]
+    fn parse_invalid_commands_returns_error() {
+        assert!(Command::parse(":tokens").is_err());
+        assert!(Command::parse(":load \n").is_err());
+        assert!(Command::parse(":reset now").is_err());
+        assert!(Command::parse(":quux").is_err());
+    }
+
+    fn complete_with(helper: &LoxHelper, line: &str) -> (usize, Vec<String>) {
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+        helper.complete(line, line.len(), &ctx).unwrap()
+    }
+
+    fn complete(line: &str) -> (usize, Vec<String>) {
+        complete_with(&LoxHelper::default(), line)
+    }
+
+    #[test]
+    fn complete_keywords() {
+        assert_eq!((0, vec!["fun".to_string()]), complete("fu"));
+        assert_eq!(
+            (
+                4,
+                vec!["false".to_string(), "for".to_string(), "fun".to_string()]
+            ),
+            complete("var f")
+        );
+        assert_eq!(
+            (5, vec!["this".to_string(), "true".to_string()]),
+            complete("a = (t")
+        );
+    }
+
+    #[test]
+    fn complete_commands() {
+        assert_eq!(
+            (0, vec![":time".to_string(), ":tokens".to_string()]),
+            complete(":t")
+        );
+        assert_eq!((8, vec!["nil".to_string()]), complete(":tokens n"));
+    }
+
+    #[test]
+    fn complete_members_without_instances_is_empty() {
+        assert_eq!((2, vec![]), complete("a.t"));
+    }
+
+    #[test]
+    fn complete_globals_and_members() {
+        let mut vm = Vm::with_output(Box::new(std::io::sink()));
+        vm.interpret(
+            "class Point { sum() {} } var point = Point(); point.size = 1; var price = 2;"
+                .to_string(),
+        )
+        .unwrap();
+        let mut helper = LoxHelper::default();
+        helper.refresh(&vm);
+        assert_eq!(
+            (
+                6,
+                vec![
+                    "point".to_string(),
+                    "price".to_string(),
+                    "print".to_string()
+                ]
+            ),
+            complete_with(&helper, "print p")
+        );
+        assert_eq!(
+            (12, vec!["size".to_string(), "sum".to_string()]),
+            complete_with(&helper, "print point.s")
+        );
+        assert_eq!((6, vec![]), complete_with(&helper, "price."));
+    }
+}
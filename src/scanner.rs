@@ -8,11 +8,15 @@ pub struct Scanner {
     line: u64,
     current: u64,
     start: u64,
+    // Where the token being scanned starts, and where the current line does.
+    start_line: u64,
+    start_column: u64,
+    line_start: u64,
     code: Vec<char>,
     pub tokens: Vec<Token>,
 }
 lazy_static! {
-    static ref KEYWORD_MAP: HashMap<&'static str, Keyword> = {
+    pub static ref KEYWORD_MAP: HashMap<&'static str, Keyword> = {
         let mut m = HashMap::new();
         m.insert("and", Keyword::And);
         m.insert("class", Keyword::Class);
@@ -42,6 +46,9 @@ impl Scanner {
             line: 1,
             current: 0,
             start: 0,
+            start_line: 1,
+            start_column: 1,
+            line_start: 0,
             tokens: vec![],
         }
     }
@@ -56,11 +63,32 @@ impl Scanner {
         c
     }
 
-    fn add_token(&mut self, token_type: TokenType) -> Result<(), ErrorType> {
+    // The code from the start of the token being scanned to the current character.
+    fn span(&self) -> Span {
+        Span {
+            line: self.start_line,
+            column: self.start_column,
+            length: self.current - self.start,
+        }
+    }
+
+    fn start_token(&mut self) {
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.current - self.line_start + 1;
+    }
+
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
+    fn add_token(&mut self, token_type: TokenType) -> Result<(), Error> {
         self.tokens.push(Token {
-            token_type: token_type,
+            token_type,
             lexeme: self.get_current_text(0, 0),
             line: self.line,
+            span: self.span(),
         });
         Ok(())
     }
@@ -93,19 +121,18 @@ impl Scanner {
         }
     }
 
-    fn add_string_literal(&mut self) -> Result<(), ErrorType> {
+    fn add_string_literal(&mut self) -> Result<(), Error> {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.new_line();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
-            return Err(ErrorType::ScanError(
-                self.line,
-                "Unterminated string.".to_string(),
-            ));
+            return Err(Error::Scan {
+                span: self.span(),
+                message: "Unterminated string.".to_string(),
+            });
         }
         self.advance();
         let text = self.get_current_text(1, -1);
@@ -120,7 +147,7 @@ impl Scanner {
         }
     }
 
-    fn add_number_literal(&mut self) -> Result<(), ErrorType> {
+    fn add_number_literal(&mut self) -> Result<(), Error> {
         while self.is_digit(self.peek()) {
             self.advance();
         }
@@ -134,14 +161,14 @@ impl Scanner {
         if let Ok(number) = text.parse::<f64>() {
             self.add_token(TokenType::Number(number))
         } else {
-            Err(ErrorType::ScanError(
-                self.line,
-                "Invalid number format.".to_string(),
-            ))
+            Err(Error::Scan {
+                span: self.span(),
+                message: "Invalid number format.".to_string(),
+            })
         }
     }
 
-    fn add_identifier(&mut self) -> Result<(), ErrorType> {
+    fn add_identifier(&mut self) -> Result<(), Error> {
         while self.is_alphanumeric(self.peek()) {
             self.advance();
         }
@@ -155,7 +182,7 @@ impl Scanner {
     }
 
     fn is_digit(&self, c: char) -> bool {
-        c.is_digit(10)
+        c.is_ascii_digit()
     }
 
     fn is_alphabetic(&self, c: char) -> bool {
@@ -166,7 +193,7 @@ impl Scanner {
         self.is_digit(c) || self.is_alphabetic(c)
     }
 
-    fn scan_token(&mut self) -> Result<(), ErrorType> {
+    fn scan_token(&mut self) -> Result<(), Error> {
         let c = self.advance();
         match c {
             '(' => self.add_token(TokenType::LeftParenthesis),
@@ -225,7 +252,7 @@ impl Scanner {
             '"' => self.add_string_literal(),
             ' ' | '\t' | '\r' => Ok(()), // Skip white spaces
             '\n' => {
-                self.line += 1;
+                self.new_line();
                 Ok(())
             }
             _ => {
@@ -234,30 +261,32 @@ impl Scanner {
                 } else if self.is_alphabetic(c) {
                     self.add_identifier()
                 } else {
-                    Err(ErrorType::ScanError(
-                        self.line,
-                        "Encountered an unknown token".to_string(),
-                    ))
+                    Err(Error::Scan {
+                        span: self.span(),
+                        message: "Encountered an unknown token".to_string(),
+                    })
                 }
             }
         }
     }
 
-    pub fn scan(&mut self) -> Result<(), ErrorType> {
+    pub fn scan(&mut self) -> Result<(), Error> {
         self.line = 1;
-        self.start = 0;
+        self.line_start = 0;
         self.current = 0;
         self.tokens = vec![];
 
         while !self.is_at_end() {
-            self.start = self.current;
+            self.start_token();
             self.scan_token()?;
         }
 
+        self.start_token();
         self.tokens.push(Token {
             token_type: TokenType::EOF,
             lexeme: String::new(),
             line: self.line,
+            span: self.span(),
         });
         Ok(())
     }
@@ -269,96 +298,183 @@ mod tests {
 
     #[test]
     fn scan_keywords() {
-        let test_code = "and class else false fun for if nil or print return super this true var while".to_string();
+        let test_code =
+            "and class else false fun for if nil or print return super this true var while"
+                .to_string();
         let mut scanner = Scanner::new(test_code);
         if let Err(_) = scanner.scan() {
             assert!(false);
         }
-        let expected:Vec<Token> = vec![
+        let expected: Vec<Token> = vec![
             Token {
                 token_type: TokenType::Keyword(Keyword::And),
                 lexeme: "and".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 1,
+                    length: 3,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::Class),
                 lexeme: "class".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 5,
+                    length: 5,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::Else),
                 lexeme: "else".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 11,
+                    length: 4,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::False),
                 lexeme: "false".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 16,
+                    length: 5,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::Fun),
                 lexeme: "fun".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 22,
+                    length: 3,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::For),
                 lexeme: "for".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 26,
+                    length: 3,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::If),
                 lexeme: "if".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 30,
+                    length: 2,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::Nil),
                 lexeme: "nil".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 33,
+                    length: 3,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::Or),
                 lexeme: "or".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 37,
+                    length: 2,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::Print),
                 lexeme: "print".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 40,
+                    length: 5,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::Return),
                 lexeme: "return".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 46,
+                    length: 6,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::Super),
                 lexeme: "super".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 53,
+                    length: 5,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::This),
                 lexeme: "this".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 59,
+                    length: 4,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::True),
                 lexeme: "true".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 64,
+                    length: 4,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::Var),
                 lexeme: "var".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 69,
+                    length: 3,
+                },
             },
             Token {
                 token_type: TokenType::Keyword(Keyword::While),
                 lexeme: "while".to_string(),
-                line: 1
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 73,
+                    length: 5,
+                },
             },
             Token {
                 token_type: TokenType::EOF,
                 lexeme: "".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 78,
+                    length: 0,
+                },
             },
         ];
         assert_eq!(expected, scanner.tokens);
@@ -377,81 +493,161 @@ mod tests {
                 token_type: TokenType::LeftParenthesis,
                 lexeme: "(".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 1,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::RightParenthesis,
                 lexeme: ")".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 2,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::LeftBrace,
                 lexeme: "{".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 3,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::RightBrace,
                 lexeme: "}".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 4,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::Comma,
                 lexeme: ",".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 5,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::Dot,
                 lexeme: ".".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 6,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::Minus,
                 lexeme: "-".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 7,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::Plus,
                 lexeme: "+".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 8,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::Semicolon,
                 lexeme: ";".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 9,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::Star,
                 lexeme: "*".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 10,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::Slash,
                 lexeme: "/".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 11,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::Bang,
                 lexeme: "!".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 12,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::Equal,
                 lexeme: "=".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 14,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::Less,
                 lexeme: "<".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 15,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::Greater,
                 lexeme: ">".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 16,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::EOF,
                 lexeme: "".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 27,
+                    length: 0,
+                },
             },
         ];
         assert_eq!(expected, scanner.tokens);
@@ -475,16 +671,31 @@ sir" "word""#
 sir""#
                     .to_string(),
                 line: 2,
+                span: Span {
+                    line: 1,
+                    column: 1,
+                    length: 11,
+                },
             },
             Token {
                 token_type: TokenType::String("word".to_string()),
                 lexeme: r#""word""#.to_string(),
                 line: 2,
+                span: Span {
+                    line: 2,
+                    column: 6,
+                    length: 6,
+                },
             },
             Token {
                 token_type: TokenType::EOF,
                 lexeme: "".to_string(),
                 line: 2,
+                span: Span {
+                    line: 2,
+                    column: 12,
+                    length: 0,
+                },
             },
         ];
         assert_eq!(expected, scanner.tokens);
@@ -500,16 +711,31 @@ sir""#
                 token_type: TokenType::Number(1.234),
                 lexeme: "1.234".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 1,
+                    length: 5,
+                },
             },
             Token {
                 token_type: TokenType::Number(1234.0),
                 lexeme: "1234".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 7,
+                    length: 4,
+                },
             },
             Token {
                 token_type: TokenType::EOF,
                 lexeme: "".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 11,
+                    length: 0,
+                },
             },
         ];
         assert_eq!(expected, scanner.tokens);
@@ -525,16 +751,31 @@ sir""#
                 token_type: TokenType::Identifier("my_var1".to_string()),
                 lexeme: "my_var1".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 1,
+                    length: 7,
+                },
             },
             Token {
                 token_type: TokenType::Identifier("thisisa123name".to_string()),
                 lexeme: "thisisa123name".to_string(),
                 line: 2,
+                span: Span {
+                    line: 2,
+                    column: 1,
+                    length: 14,
+                },
             },
             Token {
                 token_type: TokenType::EOF,
                 lexeme: "".to_string(),
                 line: 2,
+                span: Span {
+                    line: 2,
+                    column: 15,
+                    length: 0,
+                },
             },
         ];
         assert_eq!(expected, scanner.tokens);
@@ -562,26 +803,51 @@ sir""#
                 token_type: TokenType::BangEqual,
                 lexeme: "!=".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 1,
+                    length: 2,
+                },
             },
             Token {
                 token_type: TokenType::EqualEqual,
                 lexeme: "==".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 4,
+                    length: 2,
+                },
             },
             Token {
                 token_type: TokenType::LessEqual,
                 lexeme: "<=".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 7,
+                    length: 2,
+                },
             },
             Token {
                 token_type: TokenType::GreaterEqual,
                 lexeme: ">=".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 10,
+                    length: 2,
+                },
             },
             Token {
                 token_type: TokenType::EOF,
                 lexeme: "".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 15,
+                    length: 0,
+                },
             },
         ];
         assert_eq!(expected, scanner.tokens);
@@ -601,21 +867,41 @@ sir""#
                 token_type: TokenType::LeftParenthesis,
                 lexeme: "(".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 1,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::Minus,
                 lexeme: "-".to_string(),
                 line: 2,
+                span: Span {
+                    line: 2,
+                    column: 1,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::EqualEqual,
                 lexeme: "==".to_string(),
                 line: 3,
+                span: Span {
+                    line: 3,
+                    column: 1,
+                    length: 2,
+                },
             },
             Token {
                 token_type: TokenType::EOF,
                 lexeme: "".to_string(),
                 line: 4,
+                span: Span {
+                    line: 4,
+                    column: 1,
+                    length: 0,
+                },
             },
         ];
         assert_eq!(expected, scanner.tokens);
@@ -631,33 +917,74 @@ sir""#
                 token_type: TokenType::LeftParenthesis,
                 lexeme: "(".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 2,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::RightParenthesis,
                 lexeme: ")".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 4,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::LeftBrace,
                 lexeme: "{".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 8,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::RightBrace,
                 lexeme: "}".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 10,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::Comma,
                 lexeme: ",".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 14,
+                    length: 1,
+                },
             },
             Token {
                 token_type: TokenType::EOF,
                 lexeme: "".to_string(),
                 line: 1,
+                span: Span {
+                    line: 1,
+                    column: 17,
+                    length: 0,
+                },
             },
         ];
         assert_eq!(expected, scanner.tokens);
     }
+
+    #[test]
+    fn scan_errors_have_spans() {
+        let mut scanner = Scanner::new(r#"print "hello"#.to_string());
+        let span = Span {
+            line: 1,
+            column: 7,
+            length: 6,
+        };
+        assert_eq!(Some(span), scanner.scan().unwrap_err().span());
+    }
 }
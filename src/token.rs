@@ -1,4 +1,7 @@
-#[derive(Debug, PartialEq, PartialOrd)]
+use serde::Serialize;
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize)]
+#[serde(tag = "type", content = "value")]
 pub enum TokenType {
     // Single-character tokens.
     LeftParenthesis,  // '('
@@ -34,7 +37,7 @@ pub enum TokenType {
     EOF,
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
+#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize)]
 pub enum Keyword {
     And,
     Class,
@@ -54,9 +57,84 @@ pub enum Keyword {
     While,
 }
 
-#[derive(Debug, PartialEq, PartialOrd)]
+// Where a piece of code starts, by line and column counted from 1, and how
+// many characters it covers.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Serialize)]
+pub struct Span {
+    pub line: u64,
+    pub column: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize)]
 pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
+    // The line the token ends on, which differs from the line of its span
+    // only for strings spanning lines.
     pub line: u64,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TokenFormat {
+    Debug,
+    Json,
+}
+
+impl TokenFormat {
+    pub fn parse(name: &str) -> Option<TokenFormat> {
+        match name {
+            "debug" => Some(TokenFormat::Debug),
+            "json" => Some(TokenFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_tokens_as_json() {
+        let tokens = vec![
+            Token {
+                token_type: TokenType::Keyword(Keyword::Var),
+                lexeme: "var".to_string(),
+                line: 1,
+                span: Span {
+                    line: 1,
+                    column: 1,
+                    length: 3,
+                },
+            },
+            Token {
+                token_type: TokenType::Number(1.5),
+                lexeme: "1.5".to_string(),
+                line: 2,
+                span: Span {
+                    line: 2,
+                    column: 1,
+                    length: 3,
+                },
+            },
+            Token {
+                token_type: TokenType::EOF,
+                lexeme: "".to_string(),
+                line: 2,
+                span: Span {
+                    line: 2,
+                    column: 4,
+                    length: 0,
+                },
+            },
+        ];
+        let expected = concat!(
+            r#"[{"token_type":{"type":"Keyword","value":"Var"},"lexeme":"var","line":1,"span":{"line":1,"column":1,"length":3}},"#,
+            r#"{"token_type":{"type":"Number","value":1.5},"lexeme":"1.5","line":2,"span":{"line":2,"column":1,"length":3}},"#,
+            r#"{"token_type":{"type":"EOF"},"lexeme":"","line":2,"span":{"line":2,"column":4,"length":0}}]"#
+        );
+        assert_eq!(expected, serde_json::to_string(&tokens).unwrap());
+    }
 }
@@ -0,0 +1,216 @@
+use crate::object::ObjRef;
+
+// Both representations share the constructors and accessors below, so the rest
+// of the interpreter does not know which one it runs on.
+
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+impl Value {
+    pub const NIL: Value = Value::Nil;
+
+    pub fn bool(value: bool) -> Self {
+        Value::Bool(value)
+    }
+
+    pub fn number(value: f64) -> Self {
+        Value::Number(value)
+    }
+
+    pub fn obj(obj: ObjRef) -> Self {
+        Value::Obj(obj)
+    }
+
+    pub fn is_nil(self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    pub fn as_bool(self) -> Option<bool> {
+        match self {
+            Value::Bool(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(self) -> Option<f64> {
+        match self {
+            Value::Number(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_obj(self) -> Option<ObjRef> {
+        match self {
+            Value::Obj(obj) => Some(obj),
+            _ => None,
+        }
+    }
+}
+
+// A NaN-boxed value is a single word. Numbers are stored as their own bits;
+// every other value hides in the payload of a quiet NaN, with the sign bit
+// set for objects and small tags for nil, false and true.
+#[cfg(feature = "nan-boxing")]
+#[derive(Clone, Copy)]
+pub struct Value(u64);
+
+#[cfg(feature = "nan-boxing")]
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+#[cfg(feature = "nan-boxing")]
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+#[cfg(feature = "nan-boxing")]
+const TAG_NIL: u64 = 1;
+#[cfg(feature = "nan-boxing")]
+const TAG_FALSE: u64 = 2;
+#[cfg(feature = "nan-boxing")]
+const TAG_TRUE: u64 = 3;
+
+#[cfg(feature = "nan-boxing")]
+impl Value {
+    pub const NIL: Value = Value(QNAN | TAG_NIL);
+
+    pub fn bool(value: bool) -> Self {
+        Value(QNAN | if value { TAG_TRUE } else { TAG_FALSE })
+    }
+
+    // NaNs from the host can carry any payload, which could read back as one
+    // of the boxed values, so every NaN is stored as the one Rust produces.
+    pub fn number(value: f64) -> Self {
+        if value.is_nan() {
+            Value(f64::NAN.to_bits())
+        } else {
+            Value(value.to_bits())
+        }
+    }
+
+    pub fn obj(obj: ObjRef) -> Self {
+        Value(SIGN_BIT | QNAN | obj.index() as u64)
+    }
+
+    pub fn is_nil(self) -> bool {
+        self.0 == Value::NIL.0
+    }
+
+    pub fn as_bool(self) -> Option<bool> {
+        match self.0 {
+            bits if bits == QNAN | TAG_TRUE => Some(true),
+            bits if bits == QNAN | TAG_FALSE => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(self) -> Option<f64> {
+        if self.0 & QNAN != QNAN {
+            Some(f64::from_bits(self.0))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_obj(self) -> Option<ObjRef> {
+        if self.0 & (SIGN_BIT | QNAN) == SIGN_BIT | QNAN {
+            Some(ObjRef::from_index((self.0 & !(SIGN_BIT | QNAN)) as usize))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.as_number(), other.as_number()) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.0 == other.0,
+        }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.is_nil() {
+            write!(f, "Nil")
+        } else if let Some(value) = self.as_bool() {
+            write!(f, "Bool({})", value)
+        } else if let Some(value) = self.as_number() {
+            write!(f, "Number({})", value)
+        } else {
+            write!(f, "Obj({:?})", self.as_obj().unwrap())
+        }
+    }
+}
+
+impl Value {
+    pub fn is_falsey(&self) -> bool {
+        self.is_nil() || self.as_bool() == Some(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Heap;
+
+    #[test]
+    fn values_round_trip() {
+        assert!(Value::NIL.is_nil());
+        assert_eq!(Some(true), Value::bool(true).as_bool());
+        assert_eq!(Some(false), Value::bool(false).as_bool());
+        assert_eq!(Some(-2.5), Value::number(-2.5).as_number());
+        assert_eq!(None, Value::number(0.0).as_bool());
+        assert_eq!(None, Value::NIL.as_number());
+        assert_eq!(None, Value::bool(true).as_obj());
+        let mut heap = Heap::new();
+        heap.intern("a");
+        let obj = heap.intern("b");
+        assert_eq!(Some(obj), Value::obj(obj).as_obj());
+        assert_eq!(None, Value::obj(obj).as_number());
+        assert!(Value::number(f64::INFINITY)
+            .as_number()
+            .unwrap()
+            .is_infinite());
+        assert!(Value::number(f64::NAN).as_number().unwrap().is_nan());
+        assert!(Value::NIL.is_falsey());
+        assert!(Value::bool(false).is_falsey());
+        assert!(!Value::number(0.0).is_falsey());
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    #[test]
+    fn values_fit_in_one_word() {
+        assert_eq!(8, std::mem::size_of::<Value>());
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    #[test]
+    fn nans_stay_numbers() {
+        for bits in [
+            0x7ffc_0000_0000_0001,
+            0xfffc_0000_0000_0001,
+            0x7fff_ffff_ffff_ffff,
+            0xfff8_0000_0000_0000,
+        ] {
+            let value = Value::number(f64::from_bits(bits));
+            assert!(value.as_number().unwrap().is_nan());
+            assert!(!value.is_nil());
+            assert_eq!(None, value.as_bool());
+            assert_eq!(None, value.as_obj());
+        }
+    }
+
+    #[test]
+    fn equality_follows_lox_semantics() {
+        assert_eq!(Value::number(0.0), Value::number(-0.0));
+        assert_ne!(Value::number(f64::NAN), Value::number(f64::NAN));
+        assert_ne!(Value::NIL, Value::bool(false));
+        assert_ne!(Value::number(1.0), Value::bool(true));
+    }
+}
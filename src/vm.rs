@@ -0,0 +1,1774 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::bytecode;
+use crate::chunk::*;
+use crate::compiler::*;
+use crate::error::*;
+use crate::host::ClassBuilder;
+use crate::natives::{self, Capabilities};
+use crate::object::*;
+use crate::optimizer::OptLevel;
+use crate::profiler::Profiler;
+use crate::scanner::*;
+use crate::value::Value;
+
+const FRAMES_MAX: usize = 1024;
+
+// A native that calls back into Lox nests a new run of the VM on the Rust
+// stack, so these are limited to what fits in the 2 MiB stack of a spawned
+// thread, unoptimized. The Lox frames in between count towards FRAMES_MAX too.
+const NESTED_RUNS_MAX: usize = 64;
+
+// The clock is read every this many instructions when there is a time limit.
+const TIME_CHECK_INTERVAL: u64 = 256;
+
+// Settings taken from the command line.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Options {
+    pub gc: GcConfig,
+    pub opt_level: OptLevel,
+    pub profile: bool,
+    pub limits: Limits,
+    pub capabilities: Capabilities,
+}
+
+// Bounds on the work a script may do, for running untrusted code. They apply
+// to each script or call from Rust separately, including the calls it makes
+// back into Lox; None leaves a resource unbounded.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Limits {
+    // Instructions executed.
+    pub steps: Option<u64>,
+    // Live bytes on the heap, checked after collecting the garbage.
+    pub heap_bytes: Option<usize>,
+    // Nested calls, in place of the default of 1024.
+    pub call_depth: Option<usize>,
+    // Wall-clock time.
+    pub time: Option<Duration>,
+}
+
+impl Limits {
+    // Whether any limit has to be checked at every instruction.
+    fn per_instruction(&self) -> bool {
+        self.steps.is_some() || self.heap_bytes.is_some() || self.time.is_some()
+    }
+}
+
+// Stops a running script from another thread, at the next instruction, with a
+// runtime error. Requests made while no script runs are dropped.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    requested: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn new() -> Self {
+        InterruptHandle::default()
+    }
+
+    pub fn interrupt(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+    }
+
+    // Whether an interrupt was requested, clearing the request. The flag is
+    // read first, as that is cheaper than clearing it at every instruction.
+    fn take(&self) -> bool {
+        self.requested.load(Ordering::Relaxed) && self.requested.swap(false, Ordering::Relaxed)
+    }
+}
+
+struct CallFrame {
+    closure: ObjRef,
+    chunk: Rc<Chunk>,
+    ip: usize,
+    // Index of the stack slot holding the called closure, followed by the arguments.
+    slots: usize,
+}
+
+pub struct Vm {
+    heap: Heap,
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: HashMap<ObjRef, Value>,
+    // Upvalues still pointing into the stack, ordered by stack slot.
+    open_upvalues: Vec<ObjRef>,
+    init_string: ObjRef,
+    // Classes of the instances that lists and maps from Rust become.
+    list_class: ObjRef,
+    map_class: ObjRef,
+    // The natives the built-in modules installed, by global name.
+    module_natives: HashMap<&'static str, ObjRef>,
+    opt_level: OptLevel,
+    profiler: Option<Profiler>,
+    limits: Limits,
+    // Instructions executed and the time limit of the outermost call running.
+    steps: u64,
+    deadline: Option<Instant>,
+    interrupt: InterruptHandle,
+    // Calls from Rust in progress, each with its own run of the VM.
+    nested_runs: usize,
+    out: Box<dyn Write>,
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Vm::with_output(Box::new(std::io::stdout()))
+    }
+
+    pub fn with_output(out: Box<dyn Write>) -> Self {
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+        let list_class = builtin_class(&mut heap, "List");
+        let map_class = builtin_class(&mut heap, "Map");
+        let mut vm = Vm {
+            heap,
+            stack: vec![],
+            frames: vec![],
+            globals: HashMap::new(),
+            open_upvalues: vec![],
+            init_string,
+            list_class,
+            map_class,
+            module_natives: HashMap::new(),
+            opt_level: OptLevel::default(),
+            profiler: None,
+            limits: Limits::default(),
+            steps: 0,
+            deadline: None,
+            interrupt: InterruptHandle::new(),
+            nested_runs: 0,
+            out,
+        };
+        natives::define(&mut vm, Capabilities::default());
+        vm
+    }
+
+    // Makes `function` callable from Lox as the global `name`.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let name_string = self.heap.intern(name);
+        let native = self.heap.alloc(Obj::Native(NativeFunction {
+            name: name_string,
+            arity,
+            function: Rc::new(function),
+        }));
+        self.set_global(name, Value::obj(native));
+    }
+
+    // Makes the Rust type described by `class` a global Lox class.
+    pub fn define_class<T: 'static>(&mut self, class: ClassBuilder<T>) {
+        let name = class.name().to_string();
+        let class = class.build(&mut self.heap);
+        self.set_global(&name, Value::obj(class));
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let name = self.heap.find_string(name)?;
+        self.globals.get(&name).copied()
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        let name = self.heap.intern(name);
+        self.globals.insert(name, value);
+    }
+
+    pub(crate) fn remove_global(&mut self, name: &str) {
+        if let Some(name) = self.heap.find_string(name) {
+            self.globals.remove(&name);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+
+    pub fn format(&self, value: Value) -> String {
+        self.heap.format(value)
+    }
+
+    pub(crate) fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub(crate) fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    pub(crate) fn module_natives_mut(&mut self) -> &mut HashMap<&'static str, ObjRef> {
+        &mut self.module_natives
+    }
+
+    pub(crate) fn list_class(&self) -> ObjRef {
+        self.list_class
+    }
+
+    pub(crate) fn map_class(&self) -> ObjRef {
+        self.map_class
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    // Makes `handle` stop this VM instead of the handle it had, so that one
+    // handle can serve VMs that replace each other.
+    pub fn set_interrupt_handle(&mut self, handle: InterruptHandle) {
+        self.interrupt = handle;
+    }
+
+    pub fn set_options(&mut self, options: Options) {
+        self.heap.set_config(options.gc);
+        self.opt_level = options.opt_level;
+        self.limits = options.limits;
+        natives::define(self, options.capabilities);
+        match (options.profile, &self.profiler) {
+            (true, None) => self.profiler = Some(Profiler::new()),
+            (false, _) => self.profiler = None,
+            _ => {}
+        }
+    }
+
+    pub fn globals(&self) -> Vec<(String, String)> {
+        let mut globals: Vec<(String, String)> = self
+            .globals
+            .iter()
+            .map(|(name, value)| {
+                (
+                    self.heap.string(*name).to_string(),
+                    self.heap.format(*value),
+                )
+            })
+            .collect();
+        globals.sort();
+        globals
+    }
+
+    // Names of the fields and methods of the instance stored in a global, if it holds one.
+    pub fn members(&self, global: &str) -> Vec<String> {
+        let object = self
+            .globals
+            .iter()
+            .find_map(|(name, value)| match value.as_obj() {
+                Some(obj) if self.heap.string(*name) == global => Some(obj),
+                _ => None,
+            });
+        let mut members = vec![];
+        match object.map(|obj| self.heap.get(obj)) {
+            Some(Obj::Instance(instance)) => {
+                let class = self.heap.class(instance.class);
+                let fields = self.heap.shape_names(instance.shape);
+                for name in fields.iter().chain(class.methods.keys()) {
+                    members.push(self.heap.string(*name).to_string());
+                }
+            }
+            Some(Obj::Foreign(foreign)) => {
+                let class = self.heap.class(foreign.class);
+                let getters = class.host.iter().flat_map(|host| host.getters.keys());
+                for name in getters.chain(class.methods.keys()) {
+                    members.push(self.heap.string(*name).to_string());
+                }
+            }
+            _ => {}
+        }
+        members.sort();
+        members.dedup();
+        members
+    }
+
+    pub fn interpret(&mut self, code: String) -> Result<(), Vec<Error>> {
+        self.compile_and_execute(code, None)
+    }
+
+    // Runs code read from `file_name`, which stack traces then refer to.
+    pub fn interpret_file(&mut self, file_name: &str, code: String) -> Result<(), Vec<Error>> {
+        self.compile_and_execute(code, Some(file_name))
+    }
+
+    fn compile_and_execute(
+        &mut self,
+        code: String,
+        file_name: Option<&str>,
+    ) -> Result<(), Vec<Error>> {
+        let mut scanner = Scanner::new(code);
+        scanner.scan().map_err(|error| vec![error])?;
+        let mut compiler =
+            Compiler::new(&mut self.heap, scanner.tokens).with_opt_level(self.opt_level);
+        if let Some(file_name) = file_name {
+            compiler = compiler.with_file_name(file_name);
+        }
+        let function = compiler.compile()?;
+        self.execute(function)
+    }
+
+    // Runs a script loaded from a file written by `bytecode::serialize`.
+    pub fn interpret_compiled(&mut self, bytes: &[u8]) -> Result<(), Vec<Error>> {
+        let function = bytecode::deserialize(&mut self.heap, bytes).map_err(|error| vec![error])?;
+        self.execute(function)
+    }
+
+    fn execute(&mut self, function: ObjRef) -> Result<(), Vec<Error>> {
+        let closure = self.heap.alloc(Obj::Closure(Closure {
+            function,
+            upvalues: vec![],
+        }));
+        if let Some(profiler) = &mut self.profiler {
+            profiler.resume();
+        }
+        self.call_function(Value::obj(closure), &[])
+            .map(|_| ())
+            .map_err(|error| vec![error])
+    }
+
+    // Calls a Lox function, bound method or class with `args` and returns its
+    // result. Natives can use it to call back into Lox: it runs until the call
+    // returns, and leaves the frames of the caller as they were on an error.
+    pub fn call_function(&mut self, callee: Value, args: &[Value]) -> Result<Value, Error> {
+        if self.nested_runs == NESTED_RUNS_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+        self.nested_runs += 1;
+        let result = self.call_nested(callee, args);
+        self.nested_runs -= 1;
+        result
+    }
+
+    fn call_nested(&mut self, callee: Value, args: &[Value]) -> Result<Value, Error> {
+        let depth = self.frames.len();
+        let base = self.stack.len();
+        if depth == 0 {
+            self.interrupt.take();
+            self.steps = 0;
+            self.deadline = self.limits.time.map(|time| Instant::now() + time);
+        }
+        self.push(callee);
+        self.stack.extend_from_slice(args);
+        let result = self.call_value(callee, args.len()).and_then(|_| {
+            if self.frames.len() > depth {
+                self.run(depth)
+            } else {
+                // Natives and classes without an initializer return at once.
+                Ok(self.pop())
+            }
+        });
+        if result.is_err() {
+            self.close_upvalues(base);
+            self.frames.truncate(depth);
+            self.stack.truncate(base);
+        }
+        result
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = frame.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let frame = self.frame_mut();
+        let value = frame.chunk.read_u16(frame.ip);
+        frame.ip += 2;
+        value
+    }
+
+    fn read_constant(&mut self) -> Value {
+        let index = self.read_u16() as usize;
+        self.frame().chunk.constants[index]
+    }
+
+    fn read_string(&mut self) -> ObjRef {
+        let constant = self.read_constant();
+        match constant.as_obj() {
+            Some(obj) => obj,
+            None => unreachable!("expected a string constant, found {:?}", constant),
+        }
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    // Errors outside of any function, in a call from Rust, have no line.
+    fn runtime_error(&self, msg: &str) -> Error {
+        let line = match self.frames.last() {
+            Some(frame) => frame.chunk.lines[frame.ip - 1],
+            None => 0,
+        };
+        Error::Runtime {
+            line,
+            message: msg.to_string(),
+            trace: self.stack_trace(),
+        }
+    }
+
+    // The calls in progress, innermost first.
+    fn stack_trace(&self) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = self
+                    .heap
+                    .function(self.heap.closure(frame.closure).function);
+                TraceFrame {
+                    function: function.name.map(|name| self.heap.string(name).to_string()),
+                    file: frame.chunk.file.as_ref().map(|file| file.to_string()),
+                    line: frame.chunk.lines[frame.ip - 1],
+                }
+            })
+            .collect()
+    }
+
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Result<(), Error> {
+        // Methods of host classes are natives.
+        if let Obj::Native(_) = self.heap.get(closure) {
+            return self.call_native(closure, arg_count, true);
+        }
+        let function = self.heap.function(self.heap.closure(closure).function);
+        if arg_count != function.arity {
+            return Err(self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                function.arity, arg_count
+            )));
+        }
+        if self.frames.len() == self.limits.call_depth.unwrap_or(FRAMES_MAX) {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+        let chunk = Rc::clone(&function.chunk);
+        self.frames.push(CallFrame {
+            closure,
+            chunk,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+        });
+        if let Some(profiler) = &mut self.profiler {
+            profiler.call(self.heap.closure(closure).function);
+        }
+        Ok(())
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), Error> {
+        if let Some(obj) = callee.as_obj() {
+            let callee_slot = self.stack.len() - arg_count - 1;
+            match self.heap.get(obj) {
+                Obj::BoundMethod(bound) => {
+                    let method = bound.method;
+                    self.stack[callee_slot] = bound.receiver;
+                    return self.call(method, arg_count);
+                }
+                Obj::Class(class) => {
+                    // The constructor of a host class gets the class as its receiver.
+                    if let Some(host) = &class.host {
+                        let constructor = host.constructor;
+                        return self.call_native(constructor, arg_count, true);
+                    }
+                    let initializer = class.methods.get(&self.init_string).copied();
+                    let shape = class.shape;
+                    let instance = self.heap.alloc(Obj::Instance(Instance {
+                        class: obj,
+                        shape,
+                        fields: vec![],
+                    }));
+                    self.stack[callee_slot] = Value::obj(instance);
+                    return match initializer {
+                        Some(initializer) => self.call(initializer, arg_count),
+                        None if arg_count != 0 => Err(self.runtime_error(&format!(
+                            "Expected 0 arguments but got {}.",
+                            arg_count
+                        ))),
+                        None => Ok(()),
+                    };
+                }
+                Obj::Closure(_) => return self.call(obj, arg_count),
+                Obj::Native(_) => return self.call_native(obj, arg_count, false),
+                _ => {}
+            }
+        }
+        Err(self.runtime_error("Can only call functions and classes."))
+    }
+
+    // Calls a native with the `arg_count` arguments on top of the stack. A
+    // method also gets the receiver, which is in the slot below them.
+    fn call_native(&mut self, native: ObjRef, arg_count: usize, method: bool) -> Result<(), Error> {
+        let native = self.heap.native(native);
+        if arg_count != native.arity {
+            return Err(self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                native.arity, arg_count
+            )));
+        }
+        let function = Rc::clone(&native.function);
+        let callee_slot = self.stack.len() - arg_count - 1;
+        let first = if method { callee_slot } else { callee_slot + 1 };
+        let args = self.stack[first..].to_vec();
+        // Errors of the native itself happened at the call, while those of the
+        // Lox code it called back into already have their place.
+        let result = function(self, &args).map_err(|error| match error {
+            Error::Runtime { message, trace, .. } if trace.is_empty() => {
+                self.runtime_error(&message)
+            }
+            error => error,
+        })?;
+        self.stack.truncate(callee_slot);
+        self.push(result);
+        Ok(())
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        arg_count: usize,
+    ) -> Result<(), Error> {
+        match self.heap.class(class).methods.get(&name) {
+            Some(method) => self.call(*method, arg_count),
+            None => {
+                Err(self
+                    .runtime_error(&format!("Undefined property '{}'.", self.heap.string(name))))
+            }
+        }
+    }
+
+    fn invoke(&mut self, name: ObjRef, arg_count: usize, cache: usize) -> Result<(), Error> {
+        if let Some(object) = self.foreign(self.peek(arg_count)) {
+            let class = self.heap.foreign(object).class;
+            return self.invoke_from_class(class, name, arg_count);
+        }
+        let instance = self.instance(self.peek(arg_count), "Only instances have methods.")?;
+        match self.resolve_property(instance, name, cache) {
+            // A field holding a function shadows a method of the same name.
+            Some(InlineCache::Field { slot, .. }) => {
+                let field = self.heap.instance(instance).fields[slot];
+                let callee_slot = self.stack.len() - arg_count - 1;
+                self.stack[callee_slot] = field;
+                self.call_value(field, arg_count)
+            }
+            Some(InlineCache::Method { method, .. }) => self.call(method, arg_count),
+            _ => Err(self.undefined_property(name)),
+        }
+    }
+
+    fn foreign(&self, value: Value) -> Option<ObjRef> {
+        let obj = value.as_obj()?;
+        matches!(self.heap.get(obj), Obj::Foreign(_)).then_some(obj)
+    }
+
+    // Host objects have no fields: their properties are the getters and
+    // setters of their class.
+    fn host_accessor(&self, object: ObjRef, name: ObjRef, setter: bool) -> Option<ObjRef> {
+        let class = self.heap.class(self.heap.foreign(object).class);
+        let host = class.host.as_ref()?;
+        let accessors = if setter { &host.setters } else { &host.getters };
+        accessors.get(&name).copied()
+    }
+
+    fn instance(&self, value: Value, msg: &str) -> Result<ObjRef, Error> {
+        match value.as_obj() {
+            Some(obj) if matches!(self.heap.get(obj), Obj::Instance(_)) => Ok(obj),
+            _ => Err(self.runtime_error(msg)),
+        }
+    }
+
+    fn undefined_property(&self, name: ObjRef) -> Error {
+        self.runtime_error(&format!("Undefined property '{}'.", self.heap.string(name)))
+    }
+
+    // Looks `name` up on the instance, as a field first and then as a method of
+    // its class. The answer is remembered in the site's cache, which is used
+    // instead of the lookup for as long as the receivers keep the same shape.
+    fn resolve_property(
+        &self,
+        instance: ObjRef,
+        name: ObjRef,
+        cache: usize,
+    ) -> Option<InlineCache> {
+        let instance = self.heap.instance(instance);
+        let cache = &self.frame().chunk.caches[cache];
+        match cache.get() {
+            cached @ (InlineCache::Field { shape, .. } | InlineCache::Method { shape, .. })
+                if shape == instance.shape =>
+            {
+                return Some(cached)
+            }
+            _ => {}
+        }
+        let shape = instance.shape;
+        let resolved = match self.heap.shape_slot(shape, name) {
+            Some(slot) => InlineCache::Field { shape, slot },
+            None => {
+                let method = self.heap.class(instance.class).methods.get(&name)?;
+                InlineCache::Method {
+                    shape,
+                    method: *method,
+                }
+            }
+        };
+        cache.set(resolved);
+        Some(resolved)
+    }
+
+    fn set_property(&mut self, instance: ObjRef, name: ObjRef, value: Value, cache: usize) {
+        let shape = self.heap.instance(instance).shape;
+        let cached = self.frame().chunk.caches[cache].get();
+        let resolved = match cached {
+            InlineCache::Field { shape: seen, .. } | InlineCache::Transition { from: seen, .. }
+                if seen == shape =>
+            {
+                cached
+            }
+            _ => match self.heap.shape_slot(shape, name) {
+                Some(slot) => InlineCache::Field { shape, slot },
+                None => InlineCache::Transition {
+                    from: shape,
+                    to: self.heap.shape_transition(shape, name),
+                },
+            },
+        };
+        self.frame().chunk.caches[cache].set(resolved);
+        let instance = self.heap.instance_mut(instance);
+        match resolved {
+            InlineCache::Field { slot, .. } => instance.fields[slot] = value,
+            InlineCache::Transition { to, .. } => {
+                // The new field always takes the next slot.
+                instance.shape = to;
+                instance.fields.push(value);
+            }
+            _ => unreachable!("unexpected cache entry {:?}", resolved),
+        }
+    }
+
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), Error> {
+        let method = match self.heap.class(class).methods.get(&name) {
+            Some(method) => *method,
+            None => return Err(self.undefined_property(name)),
+        };
+        self.bind(method);
+        Ok(())
+    }
+
+    // Replaces the receiver on top of the stack with `method` bound to it.
+    fn bind(&mut self, method: ObjRef) {
+        let receiver = self.peek(0);
+        let bound = self
+            .heap
+            .alloc(Obj::BoundMethod(BoundMethod { receiver, method }));
+        self.pop();
+        self.push(Value::obj(bound));
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let mut insert_at = self.open_upvalues.len();
+        for (i, upvalue) in self.open_upvalues.iter().enumerate() {
+            match self.heap.get(*upvalue) {
+                Obj::Upvalue(Upvalue::Open(open_slot)) if *open_slot == slot => return *upvalue,
+                Obj::Upvalue(Upvalue::Open(open_slot)) if *open_slot > slot => {
+                    insert_at = i;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let upvalue = self.heap.alloc(Obj::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.insert(insert_at, upvalue);
+        upvalue
+    }
+
+    // Moves the values of all open upvalues at or above `last` off the stack.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last().copied() {
+            let slot = match self.heap.upvalue_mut(upvalue) {
+                Upvalue::Open(slot) if *slot >= last => *slot,
+                _ => break,
+            };
+            *self.heap.upvalue_mut(upvalue) = Upvalue::Closed(self.stack[slot]);
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn upvalue(&self, slot: usize) -> ObjRef {
+        self.heap.closure(self.frame().closure).upvalues[slot]
+    }
+
+    // The class a class declaration left on the stack. Compiled code always
+    // puts one there, but a damaged file loaded from disk need not.
+    fn class_operand(&self, value: Value) -> Result<ObjRef, Error> {
+        match value.as_obj() {
+            Some(obj) if matches!(self.heap.get(obj), Obj::Class(_)) => Ok(obj),
+            _ => Err(self.runtime_error("Expected a class.")),
+        }
+    }
+
+    fn binary_numbers(&mut self) -> Result<(f64, f64), Error> {
+        match (self.peek(1).as_number(), self.peek(0).as_number()) {
+            (Some(a), Some(b)) => {
+                self.pop();
+                self.pop();
+                Ok((a, b))
+            }
+            _ => Err(self.runtime_error("Operands must be numbers.")),
+        }
+    }
+
+    fn concatenate(&mut self, a: ObjRef, b: ObjRef) -> Option<Value> {
+        match (self.heap.get(a), self.heap.get(b)) {
+            (Obj::String(a), Obj::String(b)) => {
+                let string = format!("{}{}", a, b);
+                Some(Value::obj(self.heap.intern(&string)))
+            }
+            _ => None,
+        }
+    }
+
+    // Collections only happen between instructions, when every live value is
+    // reachable from the stack, the globals or the call frames.
+    pub(crate) fn collect_garbage(&mut self) {
+        let roots = self
+            .stack
+            .iter()
+            .chain(self.globals.values())
+            .filter_map(|value| value.as_obj())
+            .chain(self.globals.keys().copied())
+            .chain(self.open_upvalues.iter().copied())
+            .chain(self.frames.iter().map(|frame| frame.closure))
+            .chain([self.init_string, self.list_class, self.map_class])
+            .chain(self.module_natives.values().copied())
+            .chain(
+                self.profiler
+                    .iter()
+                    .flat_map(|profiler| profiler.functions()),
+            )
+            .collect::<Vec<_>>();
+        let collection = self.heap.collect(roots);
+        if self.heap.config().log {
+            eprintln!(
+                "[gc] freed {} objects, {} -> {} bytes, next at {} bytes, took {:?}",
+                collection.objects_freed,
+                collection.bytes_before,
+                collection.bytes_after,
+                collection.next_gc,
+                collection.pause
+            );
+        }
+    }
+
+    // Summary of the collector's work so far, for `--gc-log`.
+    pub fn gc_report(&self) -> Option<String> {
+        if !self.heap.config().log {
+            return None;
+        }
+        let stats = self.heap.stats();
+        Some(format!(
+            "[gc] {} collections, {} bytes allocated, {} bytes freed ({} objects), {} bytes live in {} objects, total pause {:?}, longest {:?}",
+            stats.collections,
+            stats.bytes_allocated,
+            stats.bytes_freed,
+            stats.objects_freed,
+            self.heap.bytes_allocated(),
+            self.heap.object_count(),
+            stats.total_pause,
+            stats.longest_pause
+        ))
+    }
+
+    // Where the time went, for `--profile`.
+    pub fn profile_report(&self) -> Option<String> {
+        Some(self.profiler.as_ref()?.report(&self.heap))
+    }
+
+    pub fn folded_stacks(&self) -> Option<String> {
+        Some(self.profiler.as_ref()?.folded_stacks(&self.heap))
+    }
+
+    fn profile_instruction(&mut self) {
+        let frame = self.frame();
+        let function = self.heap.closure(frame.closure).function;
+        let line = frame.chunk.lines[frame.ip];
+        let (heap, frames) = (&self.heap, &self.frames);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.instruction(function, line, || {
+                frames
+                    .iter()
+                    .map(|frame| heap.closure(frame.closure).function)
+                    .collect()
+            });
+        }
+    }
+
+    fn check_limits(&mut self) -> Result<(), Error> {
+        self.steps += 1;
+        if self.limits.steps.is_some_and(|steps| self.steps > steps) {
+            return Err(self.runtime_error("Step limit exceeded."));
+        }
+        if let Some(bytes) = self.limits.heap_bytes {
+            if self.heap.bytes_allocated() > bytes {
+                self.collect_garbage();
+                if self.heap.bytes_allocated() > bytes {
+                    return Err(self.runtime_error("Memory limit exceeded."));
+                }
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if self.steps.is_multiple_of(TIME_CHECK_INTERVAL) && Instant::now() >= deadline {
+                return Err(self.runtime_error("Time limit exceeded."));
+            }
+        }
+        Ok(())
+    }
+
+    // Runs until the frame above `depth` returns, with the value it returns.
+    fn run(&mut self, depth: usize) -> Result<Value, Error> {
+        let limited = self.limits.per_instruction();
+        loop {
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+            if self.profiler.is_some() {
+                self.profile_instruction();
+            }
+            let instruction = self.read_byte();
+            if limited {
+                self.check_limits()?;
+            }
+            if self.interrupt.take() {
+                return Err(self.runtime_error("Interrupted."));
+            }
+            let op = match OpCode::from_byte(instruction) {
+                Some(op) => op,
+                None => return Err(self.runtime_error("Unknown opcode.")),
+            };
+            match op {
+                OpCode::Constant => {
+                    let constant = self.read_constant();
+                    self.push(constant);
+                }
+                OpCode::Nil => self.push(Value::NIL),
+                OpCode::True => self.push(Value::bool(true)),
+                OpCode::False => self.push(Value::bool(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let value = self.stack[self.frame().slots + slot];
+                    self.push(value);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let index = self.frame().slots + slot;
+                    self.stack[index] = self.peek(0);
+                }
+                OpCode::GetGlobal => {
+                    let name = self.read_string();
+                    match self.globals.get(&name) {
+                        Some(value) => self.push(*value),
+                        None => {
+                            return Err(self.runtime_error(&format!(
+                                "Undefined variable '{}'.",
+                                self.heap.string(name)
+                            )))
+                        }
+                    }
+                }
+                OpCode::DefineGlobal => {
+                    let name = self.read_string();
+                    let value = self.pop();
+                    self.globals.insert(name, value);
+                }
+                OpCode::SetGlobal => {
+                    let name = self.read_string();
+                    let value = self.peek(0);
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => {
+                            return Err(self.runtime_error(&format!(
+                                "Undefined variable '{}'.",
+                                self.heap.string(name)
+                            )))
+                        }
+                    }
+                }
+                OpCode::GetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let value = match self.heap.get(self.upvalue(slot)) {
+                        Obj::Upvalue(Upvalue::Open(index)) => self.stack[*index],
+                        Obj::Upvalue(Upvalue::Closed(value)) => *value,
+                        other => unreachable!("expected an upvalue, found {:?}", other),
+                    };
+                    self.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let value = self.peek(0);
+                    let upvalue = self.upvalue(slot);
+                    match self.heap.upvalue_mut(upvalue) {
+                        Upvalue::Open(index) => {
+                            let index = *index;
+                            self.stack[index] = value;
+                        }
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                OpCode::GetProperty => {
+                    let name = self.read_string();
+                    let cache = self.read_u16() as usize;
+                    if let Some(object) = self.foreign(self.peek(0)) {
+                        match self.host_accessor(object, name, false) {
+                            Some(getter) => self.call_native(getter, 0, true)?,
+                            None => self.bind_method(self.heap.foreign(object).class, name)?,
+                        }
+                        continue;
+                    }
+                    let instance =
+                        self.instance(self.peek(0), "Only instances have properties.")?;
+                    match self.resolve_property(instance, name, cache) {
+                        Some(InlineCache::Field { slot, .. }) => {
+                            let value = self.heap.instance(instance).fields[slot];
+                            self.pop();
+                            self.push(value);
+                        }
+                        Some(InlineCache::Method { method, .. }) => self.bind(method),
+                        _ => return Err(self.undefined_property(name)),
+                    }
+                }
+                OpCode::SetProperty => {
+                    let name = self.read_string();
+                    let cache = self.read_u16() as usize;
+                    let value = self.peek(0);
+                    if let Some(object) = self.foreign(self.peek(1)) {
+                        let setter = self.host_accessor(object, name, true).ok_or_else(|| {
+                            self.runtime_error(&format!(
+                                "Property '{}' cannot be set.",
+                                self.heap.string(name)
+                            ))
+                        })?;
+                        // The setter's result is dropped for the assigned value.
+                        self.call_native(setter, 1, true)?;
+                        self.pop();
+                        self.push(value);
+                        continue;
+                    }
+                    let instance = self.instance(self.peek(1), "Only instances have fields.")?;
+                    self.set_property(instance, name, value, cache);
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::GetSuper => {
+                    let name = self.read_string();
+                    let value = self.pop();
+                    let superclass = self.class_operand(value)?;
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::Equal => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::bool(a == b));
+                }
+                OpCode::Greater => {
+                    let (a, b) = self.binary_numbers()?;
+                    self.push(Value::bool(a > b));
+                }
+                OpCode::Less => {
+                    let (a, b) = self.binary_numbers()?;
+                    self.push(Value::bool(a < b));
+                }
+                OpCode::Add => {
+                    let (a, b) = (self.peek(1), self.peek(0));
+                    let result = match (a.as_number(), b.as_number()) {
+                        (Some(a), Some(b)) => Some(Value::number(a + b)),
+                        _ => match (a.as_obj(), b.as_obj()) {
+                            (Some(a), Some(b)) => self.concatenate(a, b),
+                            _ => None,
+                        },
+                    };
+                    match result {
+                        Some(result) => {
+                            self.pop();
+                            self.pop();
+                            self.push(result);
+                        }
+                        None => {
+                            return Err(
+                                self.runtime_error("Operands must be two numbers or two strings.")
+                            )
+                        }
+                    }
+                }
+                OpCode::Subtract => {
+                    let (a, b) = self.binary_numbers()?;
+                    self.push(Value::number(a - b));
+                }
+                OpCode::Multiply => {
+                    let (a, b) = self.binary_numbers()?;
+                    self.push(Value::number(a * b));
+                }
+                OpCode::Divide => {
+                    let (a, b) = self.binary_numbers()?;
+                    self.push(Value::number(a / b));
+                }
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.push(Value::bool(value.is_falsey()));
+                }
+                OpCode::Negate => match self.peek(0).as_number() {
+                    Some(value) => {
+                        self.pop();
+                        self.push(Value::number(-value));
+                    }
+                    _ => return Err(self.runtime_error("Operand must be a number.")),
+                },
+                OpCode::Print => {
+                    let value = self.pop();
+                    let text = self.heap.format(value);
+                    writeln!(self.out, "{}", text)
+                        .map_err(|error| self.runtime_error(&error.to_string()))?;
+                }
+                OpCode::Jump => {
+                    let offset = self.read_u16() as usize;
+                    self.frame_mut().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_u16() as usize;
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_u16() as usize;
+                    self.frame_mut().ip -= offset;
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte() as usize;
+                    self.call_value(self.peek(arg_count), arg_count)?;
+                }
+                OpCode::Invoke => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte() as usize;
+                    let cache = self.read_u16() as usize;
+                    self.invoke(name, arg_count, cache)?;
+                }
+                OpCode::SuperInvoke => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte() as usize;
+                    let value = self.pop();
+                    let superclass = self.class_operand(value)?;
+                    self.invoke_from_class(superclass, name, arg_count)?;
+                }
+                OpCode::Closure => {
+                    let constant = self.read_constant();
+                    let function = match constant.as_obj() {
+                        Some(obj) => obj,
+                        None => unreachable!("expected a function, found {:?}", constant),
+                    };
+                    let upvalue_count = self.heap.function(function).upvalue_count;
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        if is_local {
+                            let slot = self.frame().slots + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(self.upvalue(index));
+                        }
+                    }
+                    let closure = self
+                        .heap
+                        .alloc(Obj::Closure(Closure { function, upvalues }));
+                    self.push(Value::obj(closure));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    if self.frames.len() == depth {
+                        return Ok(result);
+                    }
+                    self.push(result);
+                }
+                OpCode::Class => {
+                    let name = self.read_string();
+                    let shape = self.heap.root_shape();
+                    let class = self.heap.alloc(Obj::Class(Class {
+                        name,
+                        methods: HashMap::new(),
+                        shape,
+                        host: None,
+                    }));
+                    self.push(Value::obj(class));
+                }
+                OpCode::Inherit => {
+                    let superclass = match self.peek(1).as_obj() {
+                        Some(obj) => match self.heap.get(obj) {
+                            Obj::Class(class) if class.host.is_none() => class.methods.clone(),
+                            Obj::Class(_) => {
+                                return Err(self.runtime_error("Superclass cannot be a host class."))
+                            }
+                            _ => return Err(self.runtime_error("Superclass must be a class.")),
+                        },
+                        _ => return Err(self.runtime_error("Superclass must be a class.")),
+                    };
+                    let subclass = self.class_operand(self.peek(0))?;
+                    self.heap.class_mut(subclass).methods.extend(superclass);
+                    self.pop();
+                }
+                OpCode::Method => {
+                    let name = self.read_string();
+                    let class = self.class_operand(self.peek(1))?;
+                    let method = match self.peek(0).as_obj() {
+                        Some(obj) if matches!(self.heap.get(obj), Obj::Closure(_)) => obj,
+                        _ => return Err(self.runtime_error("Methods must be functions.")),
+                    };
+                    self.heap.class_mut(class).methods.insert(name, method);
+                    self.pop();
+                }
+            }
+        }
+    }
+}
+
+fn builtin_class(heap: &mut Heap, name: &str) -> ObjRef {
+    let name = heap.intern(name);
+    let shape = heap.root_shape();
+    heap.alloc(Obj::Class(Class {
+        name,
+        methods: HashMap::new(),
+        shape,
+        host: None,
+    }))
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_output::SharedOutput;
+
+    fn run(code: &str) -> (Result<(), Vec<Error>>, String) {
+        let output = SharedOutput::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        let result = vm.interpret(code.to_string());
+        let printed = String::from_utf8(output.0.borrow().clone()).unwrap();
+        (result, printed)
+    }
+
+    fn output(code: &str) -> String {
+        let (result, printed) = run(code);
+        assert!(result.is_ok(), "{:?}", result);
+        printed
+    }
+
+    fn traced_error(result: Result<(), Vec<Error>>) -> (u64, String, String) {
+        match result {
+            Err(errors) => match &errors[..] {
+                [Error::Runtime {
+                    line,
+                    message,
+                    trace,
+                }] => (*line, message.clone(), format_trace(trace)),
+                other => panic!("expected a runtime error, found {:?}", other),
+            },
+            Ok(_) => panic!("expected a runtime error"),
+        }
+    }
+
+    fn runtime_error(code: &str) -> (u64, String) {
+        let (line, msg, _) = traced_error(run(code).0);
+        (line, msg)
+    }
+
+    #[test]
+    fn interpret_arithmetic() {
+        assert_eq!(
+            "7\n-1\n2.5\ntrue\nfalse\n",
+            output("print 1 + 2 * 3; print -(3 - 2); print 10 / 4; print !nil; print 1 >= 2;")
+        );
+    }
+
+    #[test]
+    fn interpret_strings() {
+        assert_eq!(
+            "hello world\ntrue\n",
+            output(r#"var a = "hello"; print a + " world"; print a + "" == "hello";"#)
+        );
+    }
+
+    #[test]
+    fn interpret_variables_and_scopes() {
+        let code = "var a = 1;
+{
+  var a = 2;
+  { var b = a + 1; a = b; }
+  print a;
+}
+print a;";
+        assert_eq!("3\n1\n", output(code));
+    }
+
+    #[test]
+    fn interpret_control_flow() {
+        let code = "var total = 0;
+for (var i = 0; i < 5; i = i + 1) {
+  if (i == 2) total = total + 10; else total = total + i;
+}
+var j = 0;
+while (j < 3) j = j + 1;
+print total;
+print j;
+print nil or \"default\";
+print false and 1;";
+        assert_eq!("18\n3\ndefault\nfalse\n", output(code));
+    }
+
+    #[test]
+    fn interpret_functions_and_recursion() {
+        let code = "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+print fib(15);
+print fib;";
+        assert_eq!("610\n<fn fib>\n", output(code));
+    }
+
+    #[test]
+    fn interpret_closures() {
+        let code = "fun makeCounter() {
+  var count = 0;
+  fun counter() { count = count + 1; return count; }
+  return counter;
+}
+var counter = makeCounter();
+counter();
+print counter();
+var getters = nil;
+{
+  var shared = \"before\";
+  fun get() { return shared; }
+  getters = get;
+  shared = \"after\";
+}
+print getters();";
+        assert_eq!("2\nafter\n", output(code));
+    }
+
+    #[test]
+    fn interpret_classes() {
+        let code = "class Point {
+  init(x, y) { this.x = x; this.y = y; }
+  sum() { return this.x + this.y; }
+}
+var p = Point(1, 2);
+print p.sum();
+p.x = 10;
+var sum = p.sum;
+print sum();
+print p;
+print Point;";
+        assert_eq!("3\n12\nPoint instance\nPoint\n", output(code));
+    }
+
+    #[test]
+    fn interpret_inheritance() {
+        let code = "class A {
+  name() { return \"A\"; }
+  greet() { return \"I am \" + this.name(); }
+}
+class B < A {
+  name() { return \"B\"; }
+  greet() { return super.greet() + \"!\"; }
+}
+print B().greet();";
+        assert_eq!("I am B!\n", output(code));
+    }
+
+    #[test]
+    fn property_sites_follow_changing_shapes() {
+        let code =
+            "class P { init(x, y) { this.x = x; this.y = y; } sum() { return this.x + this.y; } }
+class Q { init(y) { this.y = y; this.x = 10; } sum() { return 0; } }
+fun getX(o) { return o.x; }
+fun callSum(o) { return o.sum(); }
+fun setX(o, x) { o.x = x; }
+var p = P(1, 2);
+var q = Q(5);
+print getX(p) + getX(q) + getX(p);
+print callSum(p);
+print callSum(q);
+fun shadow() { return \"field\"; }
+p.sum = shadow;
+print callSum(p);
+print callSum(P(3, 4));
+setX(p, 7);
+setX(q, 8);
+setX(p, 9);
+print getX(p) + getX(q);
+var o = P(0, 0);
+o.z = 1;
+var r = P(0, 0);
+r.z = 2;
+print o.z + r.z;";
+        assert_eq!("12\n3\n0\nfield\n7\n17\n3\n", output(code));
+    }
+
+    #[test]
+    fn interpret_runtime_errors() {
+        assert_eq!(
+            (2, "Operands must be numbers.".to_string()),
+            runtime_error("print 1;\nprint 1 - \"a\";")
+        );
+        assert_eq!(
+            (1, "Undefined variable 'x'.".to_string()),
+            runtime_error("x = 1;")
+        );
+        assert_eq!(
+            (1, "Expected 1 arguments but got 0.".to_string()),
+            runtime_error("fun f(a) {} f();")
+        );
+        assert_eq!(
+            (1, "Can only call functions and classes.".to_string()),
+            runtime_error("1();")
+        );
+        assert_eq!(
+            (1, "Undefined property 'y'.".to_string()),
+            runtime_error("class A {} A().y;")
+        );
+    }
+
+    #[test]
+    fn trace_runtime_errors() {
+        let mut vm = Vm::with_output(Box::new(std::io::sink()));
+        let code = "fun fib(n) {
+  if (n < 2) return n + nil;
+  return fib(n - 1);
+}
+fib(2);";
+        let result = vm.interpret_file("fib.lox", code.to_string());
+        assert_eq!(
+            (
+                2,
+                "Operands must be two numbers or two strings.".to_string(),
+                "[fib.lox:2] in fib()\n[fib.lox:3] in fib()\n[fib.lox:5] in script".to_string()
+            ),
+            traced_error(result)
+        );
+
+        // Errors in Lox code called back from a native keep their own place.
+        fn apply(vm: &mut Vm, args: &[Value]) -> Result<Value, Error> {
+            vm.call_function(args[0], &[])
+        }
+        vm.define_native("apply", 1, apply);
+        let result = vm.interpret("fun f() {\n  return -nil;\n}\napply(f);".to_string());
+        assert_eq!(
+            (
+                2,
+                "Operand must be a number.".to_string(),
+                "[line 2] in f()\n[line 4] in script".to_string()
+            ),
+            traced_error(result)
+        );
+    }
+
+    #[test]
+    fn report_stack_overflows_with_a_trace() {
+        let (line, msg, trace) = traced_error(run("fun f(n) {\n  return f(n + 1);\n}\nf(0);").0);
+        assert_eq!(2, line);
+        assert_eq!("Stack overflow.", msg);
+        assert_eq!(
+            vec![
+                "[line 2] in f()",
+                "[line 2] in f()",
+                "[line 2] in f()",
+                "[line 2] in f()",
+                "[line 2] in f()",
+                "... 1014 more calls ...",
+                "[line 2] in f()",
+                "[line 2] in f()",
+                "[line 2] in f()",
+                "[line 2] in f()",
+                "[line 4] in script",
+            ],
+            trace.lines().collect::<Vec<_>>()
+        );
+
+        let depth = Limits {
+            call_depth: Some(3),
+            ..Limits::default()
+        };
+        assert_eq!(
+            (1, "Stack overflow.".to_string()),
+            limited_error(depth, "fun f() { f(); } f();")
+        );
+    }
+
+    #[test]
+    fn limit_calls_back_into_lox_from_natives() {
+        fn apply(vm: &mut Vm, args: &[Value]) -> Result<Value, Error> {
+            vm.call_function(args[0], &[args[0]])
+        }
+        let mut vm = Vm::with_output(Box::new(std::io::sink()));
+        vm.define_native("apply", 1, apply);
+        let result = vm.interpret("fun f(g) { apply(g); } apply(f);".to_string());
+        let (line, msg, trace) = traced_error(result);
+        assert_eq!((1, "Stack overflow."), (line, msg.as_str()));
+        assert!(trace.starts_with("[line 1] in f()\n"), "{}", trace);
+        assert!(vm.frames.is_empty());
+        assert_eq!(0, vm.nested_runs);
+    }
+
+    fn limited_error(limits: Limits, code: &str) -> (u64, String) {
+        let mut vm = Vm::with_output(Box::new(std::io::sink()));
+        vm.set_options(Options {
+            limits,
+            ..Options::default()
+        });
+        let (line, msg, _) = traced_error(vm.interpret(code.to_string()));
+        (line, msg)
+    }
+
+    #[test]
+    fn enforce_limits() {
+        let steps = Limits {
+            steps: Some(1000),
+            ..Limits::default()
+        };
+        assert_eq!(
+            (2, "Step limit exceeded.".to_string()),
+            limited_error(steps, "var i = 0;\nwhile (true) i = i + 1;")
+        );
+        let heap = Limits {
+            heap_bytes: Some(64 * 1024),
+            ..Limits::default()
+        };
+        assert_eq!(
+            (1, "Memory limit exceeded.".to_string()),
+            limited_error(heap, "var s = \"x\"; while (true) s = s + s;")
+        );
+        let time = Limits {
+            time: Some(Duration::from_millis(10)),
+            ..Limits::default()
+        };
+        assert_eq!(
+            (1, "Time limit exceeded.".to_string()),
+            limited_error(time, "while (true) {}")
+        );
+    }
+
+    #[test]
+    fn limits_apply_to_each_script() {
+        let output = SharedOutput::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.set_options(Options {
+            limits: Limits {
+                steps: Some(5_000),
+                heap_bytes: Some(64 * 1024),
+                ..Limits::default()
+            },
+            ..Options::default()
+        });
+        // Each script makes more garbage than the heap may hold, and runs
+        // more instructions than a third of the budget.
+        let garbage = "var s = \"x\";
+for (var i = 0; i < 10; i = i + 1) s = s + s;
+for (var i = 0; i < 200; i = i + 1) { var t = s + \"y\"; }";
+        for _ in 0..3 {
+            vm.interpret(garbage.to_string()).unwrap();
+        }
+        assert!(vm.interpret("while (true) {}".to_string()).is_err());
+        vm.interpret("print 1;".to_string()).unwrap();
+        assert_eq!(b"1\n".to_vec(), *output.0.borrow());
+    }
+
+    #[test]
+    fn interrupt_from_another_thread() {
+        let mut vm = Vm::with_output(Box::new(std::io::sink()));
+        let handle = vm.interrupt_handle();
+        // Requests made before the script starts are dropped, so keep asking
+        // until it has stopped.
+        let stopped = Arc::new(AtomicBool::new(false));
+        let interrupter = std::thread::spawn({
+            let stopped = stopped.clone();
+            move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    handle.interrupt();
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let result = vm.interpret("var i = 0;\nwhile (true) {\n  i = i + 1;\n}".to_string());
+        stopped.store(true, Ordering::Relaxed);
+        interrupter.join().unwrap();
+        let (_, msg, _) = traced_error(result);
+        assert_eq!("Interrupted.", msg);
+
+        // The VM stays usable, and requests made between scripts are dropped.
+        vm.interrupt_handle().interrupt();
+        vm.interpret("var j = 1;".to_string()).unwrap();
+        assert!(vm.get_global("j").is_some());
+    }
+
+    #[test]
+    fn interpret_keeps_globals_between_calls() {
+        let output = SharedOutput::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.interpret("var a = 1;".to_string()).unwrap();
+        assert!(vm.interpret("print b;".to_string()).is_err());
+        vm.interpret("print a;".to_string()).unwrap();
+        assert_eq!(b"1\n".to_vec(), *output.0.borrow());
+        let expected = vec![
+            ("a".to_string(), "1".to_string()),
+            ("clock".to_string(), "<native fn>".to_string()),
+        ];
+        assert_eq!(expected, vm.globals());
+    }
+
+    #[test]
+    fn interpret_compiled_scripts() {
+        let mut heap = Heap::new();
+        let mut scanner = Scanner::new("fun f(a) { return a + 1; } print f(1);".to_string());
+        scanner.scan().unwrap();
+        let function = Compiler::new(&mut heap, scanner.tokens).compile().unwrap();
+        let bytes = bytecode::serialize(&heap, function);
+
+        let output = SharedOutput::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.interpret_compiled(&bytes).unwrap();
+        assert_eq!(b"2\n".to_vec(), *output.0.borrow());
+    }
+
+    #[test]
+    fn damaged_class_code_is_a_runtime_error() {
+        let run = |code: &[OpCode]| {
+            let mut heap = Heap::new();
+            let mut chunk = Chunk::new();
+            chunk.constants.push(Value::obj(heap.intern("name")));
+            for op in code {
+                chunk.write(*op as u8, 1);
+                if matches!(op, OpCode::GetSuper | OpCode::Class | OpCode::Method) {
+                    chunk.write(0, 1);
+                    chunk.write(0, 1);
+                }
+            }
+            let function = heap.alloc(Obj::Function(Function {
+                arity: 0,
+                upvalue_count: 0,
+                chunk: Rc::new(chunk),
+                name: None,
+            }));
+            let bytes = bytecode::serialize(&heap, function);
+            let mut vm = Vm::with_output(Box::new(SharedOutput::default()));
+            match &vm.interpret_compiled(&bytes).unwrap_err()[..] {
+                [error] => error.message().to_string(),
+                errors => panic!("unexpected errors {:?}", errors),
+            }
+        };
+        use OpCode::*;
+        assert_eq!("Expected a class.", run(&[Nil, Nil, GetSuper, Return]));
+        assert_eq!("Expected a class.", run(&[Class, Nil, Inherit, Return]));
+        assert_eq!(
+            "Methods must be functions.",
+            run(&[Class, Nil, Method, Return])
+        );
+    }
+
+    #[test]
+    fn collect_garbage_while_running() {
+        let code = "class Node { init(next) { this.next = next; } }
+fun build(n) {
+  var head = nil;
+  for (var i = 0; i < n; i = i + 1) head = Node(head);
+  // Close the list into a cycle.
+  var last = head;
+  while (last.next != nil) last = last.next;
+  last.next = head;
+  return head;
+}
+var kept = build(10);
+for (var i = 0; i < 50; i = i + 1) build(20);
+var count = 1;
+var node = kept.next;
+while (node != kept) { count = count + 1; node = node.next; }
+print count;
+print \"a\" + \"b\";";
+        let output = SharedOutput::default();
+        let config = GcConfig {
+            stress: true,
+            ..GcConfig::default()
+        };
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.set_options(Options {
+            gc: config,
+            ..Options::default()
+        });
+        vm.interpret(code.to_string()).unwrap();
+        assert_eq!(b"10\nab\n".to_vec(), *output.0.borrow());
+        vm.collect_garbage();
+        // The garbage lists are gone, only the kept one and a few globals and strings remain.
+        assert!(vm.heap.object_count() < 100);
+    }
+
+    #[test]
+    fn shapes_of_dead_classes_are_collected() {
+        let mut vm = Vm::with_output(Box::new(SharedOutput::default()));
+        vm.set_options(Options {
+            limits: Limits {
+                heap_bytes: Some(64 * 1024),
+                ..Limits::default()
+            },
+            ..Options::default()
+        });
+        // Every class declaration makes new shapes for its instances.
+        let code = "for (var i = 0; i < 10000; i = i + 1) {
+  class C {} var c = C(); c.a = 1; c.b = 2;
+}";
+        vm.interpret(code.to_string()).unwrap();
+        vm.collect_garbage();
+        assert!(vm.heap.object_count() < 100);
+        assert!(vm.heap.bytes_allocated() < 16 * 1024);
+    }
+
+    #[test]
+    fn members_of_global_instances() {
+        let mut vm = Vm::with_output(Box::new(SharedOutput::default()));
+        vm.interpret("class A { m() {} } var a = A(); a.f = 1; var n = 1;".to_string())
+            .unwrap();
+        assert_eq!(vec!["f".to_string(), "m".to_string()], vm.members("a"));
+        assert!(vm.members("n").is_empty());
+    }
+
+    #[test]
+    fn optimized_code_behaves_the_same() {
+        let code = "var a = 2;
+print 1 + 2 * 3 == 7 and \"x\" + \"y\";
+print nil or 1 + 2;
+print (a or 1) + 2;
+if (!true) print \"no\"; else print \"yes\";
+while (false) print \"never\";
+for (var i = 0; i < 2; i = i + 1) print i * (3 - 1);
+fun f() { return \"f\"; print \"unreachable\"; }
+print f();";
+        let mut outputs = vec![];
+        for opt_level in [OptLevel::O0, OptLevel::O1] {
+            let output = SharedOutput::default();
+            let mut vm = Vm::with_output(Box::new(output.clone()));
+            vm.set_options(Options {
+                opt_level,
+                ..Options::default()
+            });
+            vm.interpret(code.to_string()).unwrap();
+            outputs.push(String::from_utf8(output.0.borrow().clone()).unwrap());
+        }
+        assert_eq!("xy\n3\n4\nyes\n0\n2\nf\n", outputs[0]);
+        assert_eq!(outputs[0], outputs[1]);
+
+        assert_eq!(
+            (1, "Operands must be numbers.".to_string()),
+            runtime_error("print \"a\" - 1;")
+        );
+        assert_eq!(
+            (1, "Operand must be a number.".to_string()),
+            runtime_error("print -\"a\";")
+        );
+    }
+
+    #[test]
+    fn profile_functions_and_lines() {
+        let code = "fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 2) + fib(n - 1);
+}
+print fib(15);";
+        let mut vm = Vm::with_output(Box::new(SharedOutput::default()));
+        assert!(vm.profile_report().is_none());
+        vm.set_options(Options {
+            profile: true,
+            gc: GcConfig {
+                stress: true,
+                ..GcConfig::default()
+            },
+            ..Options::default()
+        });
+        vm.interpret(code.to_string()).unwrap();
+
+        let report = vm.profile_report().unwrap();
+        assert!(report.contains("       1973         "), "{}", report);
+        assert!(report.contains("fib:2\n"), "{}", report);
+        assert!(report.contains("  2 in fib"), "{}", report);
+        let stacks = vm.folded_stacks().unwrap();
+        assert!(
+            stacks
+                .lines()
+                .all(|line| line.starts_with("script;fib:2") || line.starts_with("script ")),
+            "{}",
+            stacks
+        );
+    }
+
+    #[test]
+    fn call_native_functions() {
+        let (result, printed) = run("var start = clock();
+print start > 0;
+print clock() >= start;
+print clock;");
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!("true\ntrue\n<native fn>\n", printed);
+
+        fn add(vm: &mut Vm, args: &[Value]) -> Result<Value, Error> {
+            match (args[0].as_number(), args[1].as_number()) {
+                (Some(a), Some(b)) => Ok(Value::number(a + b)),
+                _ => {
+                    let msg = format!("Cannot add {}.", vm.heap.format(args[1]));
+                    Err(Error::runtime(msg))
+                }
+            }
+        }
+        let output = SharedOutput::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.define_native("add", 2, add);
+        vm.interpret("print add(1, add(2, 3));".to_string())
+            .unwrap();
+        assert_eq!(b"6\n".to_vec(), *output.0.borrow());
+
+        let result = vm.interpret("\nadd(1, \"two\");".to_string());
+        assert_eq!(
+            (
+                2,
+                "Cannot add two.".to_string(),
+                "[line 2] in script".to_string()
+            ),
+            traced_error(result)
+        );
+        assert_eq!(
+            (1, "Expected 0 arguments but got 1.".to_string()),
+            runtime_error("clock(1);")
+        );
+    }
+}